@@ -0,0 +1,26 @@
+use crate::config::{AclConfig, AclDefault};
+use crate::handler::MessageKind;
+
+impl AclConfig {
+    /// Whether a connection with `identity` may send requests of `kind`
+    pub fn allows(&self, kind: MessageKind, identity: Option<&str>) -> bool {
+        match self.messages.get(&kind) {
+            Some(patterns) => patterns.iter().any(|pattern| matches(pattern, identity)),
+            None => self.default == AclDefault::Allow,
+        }
+    }
+}
+
+/// Matches an identity against an exact name, a `prefix*` or `*`; only `*` matches a connection without one
+fn matches(pattern: &str, identity: Option<&str>) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    let Some(identity) = identity else {
+        return false;
+    };
+    match pattern.strip_suffix('*') {
+        Some(prefix) => identity.starts_with(prefix),
+        None => identity == pattern,
+    }
+}
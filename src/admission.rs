@@ -0,0 +1,112 @@
+use crate::config::{ConnectionLimitPolicy, ServerConfig};
+use crate::metrics::ServerMetrics;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Decides which accepted connections the server serves, and counts those it does in the metrics
+pub(crate) struct Admission {
+    slots: Option<Arc<Semaphore>>, // One permit per connection when max_connections is set
+    max_connections: Option<usize>,
+    policy: ConnectionLimitPolicy,
+    max_per_ip: Option<usize>,
+    per_ip: Mutex<HashMap<IpAddr, usize>>, // Open connections of each client address
+    metrics: Arc<ServerMetrics>,
+}
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    ServerFull(usize),
+    AddressFull(usize),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::ServerFull(max) => write!(f, "Server is serving its maximum of {} connections", max),
+            Refusal::AddressFull(max) => write!(f, "Address already has its maximum of {} connections", max),
+        }
+    }
+}
+
+/// An admitted connection; dropping it frees its place
+pub(crate) struct Admitted {
+    admission: Arc<Admission>,
+    ip: IpAddr,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Admission {
+    pub fn new(config: &ServerConfig, metrics: Arc<ServerMetrics>) -> Self {
+        Admission {
+            slots: config.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            max_connections: config.max_connections,
+            policy: config.connection_limit_policy,
+            max_per_ip: config.max_connections_per_ip,
+            per_ip: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    pub fn policy(&self) -> ConnectionLimitPolicy {
+        self.policy
+    }
+
+    /// Under the queue policy, waits for a free slot before the next client is accepted
+    pub async fn wait_for_slot(&self) -> Option<OwnedSemaphorePermit> {
+        match (&self.slots, self.policy) {
+            (Some(slots), ConnectionLimitPolicy::Queue) => Some(
+                slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("connection semaphore is never closed"),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Admits a client from `ip`, using the slot it queued for if there is one
+    pub fn admit(self: &Arc<Self>, ip: IpAddr, queued: Option<OwnedSemaphorePermit>) -> Result<Admitted, Refusal> {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        let open = per_ip.get(&ip).copied().unwrap_or(0);
+        if let Some(max) = self.max_per_ip.filter(|&max| open >= max) {
+            return Err(Refusal::AddressFull(max));
+        }
+
+        let slot = match (queued, &self.slots) {
+            (Some(permit), _) => Some(permit),
+            (None, Some(slots)) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => return Err(Refusal::ServerFull(self.max_connections.unwrap_or_default())),
+            },
+            (None, None) => None,
+        };
+
+        per_ip.insert(ip, open + 1);
+        self.metrics.record_connection_opened();
+        Ok(Admitted {
+            admission: self.clone(),
+            ip,
+            _slot: slot,
+        })
+    }
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        let mut per_ip = self.admission.per_ip.lock().unwrap();
+        if let Some(open) = per_ip.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+        self.admission.metrics.record_connection_closed();
+    }
+}
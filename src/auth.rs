@@ -0,0 +1,128 @@
+use crate::handler::HandlerError;
+use crate::message::ErrorCode;
+use async_trait::async_trait;
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{collections::HashMap, fs, io, path::Path};
+
+/// Bytes of the nonce in an `AuthChallenge`
+pub const CHALLENGE_SIZE: usize = 32;
+
+/// What a client presented in an `Authenticate`
+#[derive(Debug, Clone, Copy)]
+pub enum Credentials<'a> {
+    /// A bearer token, checked as is
+    Token(&'a str),
+    /// An HMAC-SHA256 of `challenge`, keyed with the secret of `key_id`
+    Hmac {
+        key_id: &'a str,
+        challenge: &'a [u8],
+        signature: &'a [u8],
+    },
+}
+
+/// Checks the credentials of connections that did not present a client certificate.
+///
+/// Set on the server with [`ServerBuilder::authenticator`](crate::server::ServerBuilder::authenticator);
+/// [`StaticTokenAuthenticator`] serves tokens from a file.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns the identity the credentials prove, or why they were refused
+    async fn authenticate(&self, credentials: Credentials<'_>) -> Result<String, HandlerError>;
+}
+
+/// Accepts a fixed set of identities, each with a secret that works both as a
+/// bearer token and as the HMAC key of the identity.
+///
+/// The token file is TOML mapping each identity to its secret:
+///
+/// ```toml
+/// "device-42" = "4f1c9e0b7a"
+/// "sensor-7" = "d2e8a61c55"
+/// ```
+pub struct StaticTokenAuthenticator {
+    secrets: HashMap<String, String>, // By identity
+}
+
+impl StaticTokenAuthenticator {
+    /// Accepts the given identities and secrets; an empty secret is refused
+    pub fn new(secrets: impl IntoIterator<Item = (String, String)>) -> io::Result<Self> {
+        let secrets: HashMap<String, String> = secrets.into_iter().collect();
+        // An empty secret would let anyone in with an empty token
+        if let Some((identity, _)) = secrets.iter().find(|(_, secret)| secret.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Empty secret for {}", identity),
+            ));
+        }
+        Ok(StaticTokenAuthenticator { secrets })
+    }
+
+    /// Reads the identities and secrets of a token file
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to read {}: {}", path.display(), e)))?;
+        let secrets: HashMap<String, String> = toml::from_str(&text).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid token file {}: {}", path.display(), e))
+        })?;
+        StaticTokenAuthenticator::new(secrets)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{} in {}", e, path.display())))
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticTokenAuthenticator {
+    async fn authenticate(&self, credentials: Credentials<'_>) -> Result<String, HandlerError> {
+        match credentials {
+            Credentials::Token(token) => {
+                // Every secret is compared, so the time taken does not tell which one came close
+                let mut identity = None;
+                for (candidate, secret) in &self.secrets {
+                    if constant_time_eq(secret.as_bytes(), token.as_bytes()) {
+                        identity = Some(candidate);
+                    }
+                }
+                identity
+                    .cloned()
+                    .ok_or_else(|| HandlerError::new(ErrorCode::Unauthenticated, "Invalid token"))
+            }
+            Credentials::Hmac { key_id, challenge, signature } => {
+                let verified = self
+                    .secrets
+                    .get(key_id)
+                    .is_some_and(|secret| verify_challenge(secret.as_bytes(), challenge, signature));
+                if verified {
+                    Ok(key_id.to_string())
+                } else {
+                    Err(HandlerError::new(ErrorCode::Unauthenticated, "Invalid signature"))
+                }
+            }
+        }
+    }
+}
+
+/// Signs an `AuthChallenge` nonce with `secret`, giving the signature for `HmacSignature`
+pub fn sign_challenge(secret: &[u8], nonce: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret), nonce).as_ref().to_vec()
+}
+
+/// Checks a signature made by [`sign_challenge`], in constant time
+pub fn verify_challenge(secret: &[u8], nonce: &[u8], signature: &[u8]) -> bool {
+    hmac::verify(&hmac::Key::new(hmac::HMAC_SHA256, secret), nonce, signature).is_ok()
+}
+
+/// A fresh random nonce for an `AuthChallenge`
+pub(crate) fn challenge() -> Result<Vec<u8>, HandlerError> {
+    let mut nonce = vec![0u8; CHALLENGE_SIZE];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| HandlerError::new(ErrorCode::UnknownError, "No randomness available for a challenge"))?;
+    Ok(nonce)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,277 @@
+use crate::error::{response_message, ClientError, MAX_RESPONSE_SIZE, PUSH_BUFFER_SIZE};
+use crate::framing::{encode_frame, FrameBuffer};
+use crate::handshake::client_hello;
+use crate::message::{
+    authenticate, client_message, server_message, AddRequest, AddResponse, Authenticate, ClientMessage, EchoMessage,
+    HelloAck, Publish, ServerMessage, Subscribe, TopicAck, Unsubscribe,
+};
+use log::{error, info};
+use prost::Message;
+use std::io::{Read, Write};
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// Blocking TCP/IP client built on `std::net`, for consumers that cannot run tokio.
+///
+/// The timeout given to [`Client::new`] bounds connecting and every socket
+/// read and write. Messages the server pushes while a request waits for its
+/// response are kept for [`Client::take_pushes`].
+pub struct Client {
+    ip: String,
+    port: u32,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+    frames: FrameBuffer, // reassembles response frames split or coalesced by TCP
+    pushes: VecDeque<ServerMessage>,
+}
+
+impl Client {
+    pub fn new(ip: &str, port: u32, timeout_ms: u64) -> Self {
+        Client {
+            ip: ip.to_string(),
+            port,
+            timeout: Duration::from_millis(timeout_ms),
+            stream: None,
+            frames: FrameBuffer::new(MAX_RESPONSE_SIZE),
+            pushes: VecDeque::new(),
+        }
+    }
+
+    // connect the client to the server
+    pub fn connect(&mut self) -> io::Result<()> {
+        info!("Connecting to {}:{}", self.ip, self.port);
+
+        // Resolve the address
+        let address = format!("{}:{}", self.ip, self.port);
+        let socket_addrs: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+
+        if socket_addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid IP or port",
+            ));
+        }
+
+        // Connect to the server with a timeout, and apply it to reads and writes too
+        let stream = TcpStream::connect_timeout(&socket_addrs[0], self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        self.stream = Some(stream);
+        self.frames = FrameBuffer::new(MAX_RESPONSE_SIZE); // drop leftovers from an earlier connection
+
+        info!("Connected to the server!");
+        Ok(())
+    }
+
+    // disconnect the client
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        if let Some(stream) = self.stream.take() {
+            stream.shutdown(std::net::Shutdown::Both)?;
+        }
+
+        info!("Disconnected from the server!");
+        Ok(())
+    }
+
+    /// Whether `connect` succeeded and the connection has not been closed since
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// The underlying socket, while connected
+    pub fn get_ref(&self) -> Option<&TcpStream> {
+        self.stream.as_ref()
+    }
+
+    // generic message to send message to the server
+    pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
+        if let Some(ref mut stream) = self.stream {
+            // Encode the message into a length-delimited frame
+            let buffer = encode_frame(&ClientMessage {
+                message: Some(message),
+                request_id: 0, // one request at a time, so responses come back in order
+            });
+
+            // Send the buffer to the server
+            stream.write_all(&buffer)?;
+            stream.flush()?;
+
+            info!("Sent {} bytes", buffer.len());
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No active connection",
+            ))
+        }
+    }
+
+    pub fn receive(&mut self) -> io::Result<ServerMessage> {
+        if let Some(ref mut stream) = self.stream {
+            info!("Receiving message from the server");
+            let mut buffer = vec![0u8; 1024];
+
+            // Keep reading until a whole frame has been reassembled
+            let frame = loop {
+                if let Some(frame) = self.frames.next_frame()? {
+                    break frame;
+                }
+
+                let bytes_read = stream.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    info!("Server disconnected.");
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Server disconnected",
+                    ));
+                }
+
+                info!("Received {} bytes from the server", bytes_read);
+                self.frames.extend(&buffer[..bytes_read]);
+            };
+
+            // Decode the received message
+            ServerMessage::decode(frame.as_slice()).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Failed to decode ServerMessage: {}", e),
+                )
+            })
+        } else {
+            error!("No active connection");
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No active connection",
+            ))
+        }
+    }
+
+    /// Performs the handshake; call it right after `connect`, before any other request.
+    ///
+    /// The answer tells the protocol version, the server's frame limit, the message
+    /// types it handles and the optional features it enabled.
+    pub fn hello(&mut self) -> Result<HelloAck, ClientError> {
+        let message = client_message::Message::Hello(client_hello(MAX_RESPONSE_SIZE));
+        match self.request(message)? {
+            server_message::Message::HelloAck(ack) => Ok(ack),
+            other => Err(ClientError::UnexpectedResponse(Some(other))),
+        }
+    }
+
+    /// Authenticates with a bearer token, returning the identity the server now knows the client as.
+    ///
+    /// A refused token fails with an `UNAUTHENTICATED` [`ClientError::Server`] and the server closes the connection.
+    pub fn authenticate(&mut self, token: &str) -> Result<String, ClientError> {
+        let message = client_message::Message::Authenticate(Authenticate {
+            credentials: Some(authenticate::Credentials::Token(token.to_string())),
+        });
+        match self.request(message)? {
+            server_message::Message::AuthResult(result) => Ok(result.identity),
+            other => Err(ClientError::UnexpectedResponse(Some(other))),
+        }
+    }
+
+    /// Echoes `content` through the server
+    pub fn echo(&mut self, content: &str) -> Result<String, ClientError> {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        });
+        match self.request(message)? {
+            server_message::Message::EchoMessage(echo) => Ok(echo.content),
+            other => Err(ClientError::UnexpectedResponse(Some(other))),
+        }
+    }
+
+    /// Adds `a` and `b` on the server; the response tells how an overflow was handled
+    pub fn add(&mut self, a: i32, b: i32) -> Result<AddResponse, ClientError> {
+        let message = client_message::Message::AddRequest(AddRequest { a, b });
+        match self.request(message)? {
+            server_message::Message::AddResponse(add_response) => Ok(add_response),
+            other => Err(ClientError::UnexpectedResponse(Some(other))),
+        }
+    }
+
+    /// Subscribes to the topics matching `filter`, returning how many retained
+    /// publications were pushed; publications arrive through `take_pushes`
+    pub fn subscribe(&mut self, filter: &str) -> Result<u32, ClientError> {
+        let message = client_message::Message::Subscribe(Subscribe {
+            filter: filter.to_string(),
+        });
+        Ok(self.topic_request(message)?.delivered)
+    }
+
+    /// Stops receiving the topics matching `filter`; other filters still apply
+    pub fn unsubscribe(&mut self, filter: &str) -> Result<(), ClientError> {
+        let message = client_message::Message::Unsubscribe(Unsubscribe {
+            filter: filter.to_string(),
+        });
+        self.topic_request(message)?;
+        Ok(())
+    }
+
+    /// Publishes `payload` on `topic`, returning how many subscribers it was queued for
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<u32, ClientError> {
+        let message = client_message::Message::Publish(Publish {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            retain,
+        });
+        Ok(self.topic_request(message)?.delivered)
+    }
+
+    fn topic_request(&mut self, message: client_message::Message) -> Result<TopicAck, ClientError> {
+        match self.request(message)? {
+            server_message::Message::TopicAck(ack) => Ok(ack),
+            other => Err(ClientError::UnexpectedResponse(Some(other))),
+        }
+    }
+
+    /// Sends `message` and waits for its response.
+    ///
+    /// An `ErrorResponse` from the server is returned as [`ClientError::Server`].
+    /// After a timeout or a socket error the connection is closed, because a
+    /// late response would otherwise be read as the answer to the next request.
+    pub fn request(&mut self, message: client_message::Message) -> Result<server_message::Message, ClientError> {
+        if self.stream.is_none() {
+            return Err(ClientError::Disconnected);
+        }
+
+        let result = self.send(message).and_then(|_| self.receive_response());
+        match result {
+            Ok(response) => response_message(response),
+            Err(e) => {
+                self.stream = None;
+                Err(match e.kind() {
+                    // Unix reports an expired socket timeout as WouldBlock, Windows as TimedOut
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ClientError::Timeout(self.timeout),
+                    io::ErrorKind::ConnectionAborted => ClientError::Disconnected,
+                    _ => ClientError::Io(e),
+                })
+            }
+        }
+    }
+
+    /// Messages pushed by the server that arrived while waiting for responses, oldest first
+    pub fn take_pushes(&mut self) -> Vec<ServerMessage> {
+        self.pushes.drain(..).collect()
+    }
+
+    /// Receives frames until one answers a request, setting pushed messages aside
+    fn receive_response(&mut self) -> io::Result<ServerMessage> {
+        loop {
+            let message = self.receive()?;
+            if !message.push {
+                return Ok(message);
+            }
+            if self.pushes.len() == PUSH_BUFFER_SIZE {
+                self.pushes.pop_front();
+            }
+            self.pushes.push_back(message);
+        }
+    }
+}
@@ -0,0 +1,405 @@
+pub use crate::error::ClientError;
+use crate::auth::sign_challenge;
+use crate::error::{response_message, MAX_RESPONSE_SIZE, PUSH_BUFFER_SIZE};
+use crate::framing::{encode_frame, FrameBuffer};
+use crate::handshake::client_hello;
+use crate::message::{
+    authenticate, client_message, server_message, AddRequest, AddResponse, Authenticate, ClientMessage, EchoMessage,
+    HelloAck, HmacSignature, Publish, ServerMessage, Subscribe, TopicAck, Unsubscribe,
+};
+use log::{debug, error, info, warn};
+use prost::Message;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use crate::tls::{ClientTls, Transport};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+/// Timeout applied to connecting and to each call unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests queued for the connection's writer before callers wait
+const OUTBOX_SIZE: usize = 64;
+
+/// Asynchronous client for the server protocol.
+///
+/// Connecting performs the `Hello` handshake; the server's answer is kept in
+/// [`Client::server_info`]. Every request carries its own id, so calls can be
+/// made concurrently from several tasks sharing the client; responses are
+/// matched back to their call by id, whatever order the server answers in.
+/// Each call is bounded by the client's timeout. Messages the server pushes on
+/// its own are delivered to the receiver returned by [`Client::take_pushes`].
+pub struct Client {
+    outbox: Option<mpsc::Sender<Vec<u8>>>, // Encoded requests for the writer task
+    pending: Arc<Pending>,
+    reader: JoinHandle<()>,
+    writer: Option<JoinHandle<io::Result<()>>>,
+    next_request_id: AtomicU64,
+    timeout: Duration,
+    server_info: HelloAck, // What the server agreed to in the handshake
+    pushes: Option<mpsc::Receiver<ServerMessage>>, // Until taken by the caller
+}
+
+/// Calls waiting for their response, by request id; `None` once the connection is closed
+type Waiters = Option<HashMap<u64, oneshot::Sender<ServerMessage>>>;
+
+#[derive(Default)]
+struct Pending {
+    waiters: Mutex<Waiters>,
+}
+
+impl Pending {
+    fn insert(&self, request_id: u64) -> Option<oneshot::Receiver<ServerMessage>> {
+        let (sender, receiver) = oneshot::channel();
+        let mut waiters = self.waiters.lock().unwrap();
+        waiters.as_mut()?.insert(request_id, sender);
+        Some(receiver)
+    }
+
+    fn remove(&self, request_id: u64) -> Option<oneshot::Sender<ServerMessage>> {
+        self.waiters.lock().unwrap().as_mut()?.remove(&request_id)
+    }
+
+    fn is_open(&self) -> bool {
+        self.waiters.lock().unwrap().is_some()
+    }
+
+    /// Marks the connection closed; the calls still waiting fail with `Disconnected`
+    fn close(&self) {
+        self.waiters.lock().unwrap().take();
+    }
+}
+
+impl Client {
+    /// Connects with the default timeout
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, ClientError> {
+        Self::connect_with_timeout(addr, DEFAULT_TIMEOUT).await
+    }
+
+    /// Connects and performs the handshake, using `timeout` both for the connection attempt and for each later call
+    pub async fn connect_with_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> Result<Self, ClientError> {
+        let stream = connect_tcp(addr, timeout).await?;
+        Self::start(Box::new(stream), timeout).await
+    }
+
+    /// Connects over TLS with the default timeout
+    pub async fn connect_tls(addr: impl ToSocketAddrs, tls: &ClientTls) -> Result<Self, ClientError> {
+        Self::connect_tls_with_timeout(addr, tls, DEFAULT_TIMEOUT).await
+    }
+
+    /// Connects over TLS, verifying the server's certificate against `tls`, then performs the handshake.
+    ///
+    /// A certificate that does not verify fails the call with [`ClientError::Io`].
+    pub async fn connect_tls_with_timeout(
+        addr: impl ToSocketAddrs,
+        tls: &ClientTls,
+        timeout: Duration,
+    ) -> Result<Self, ClientError> {
+        let stream = connect_tcp(addr, timeout).await?;
+        let stream = tokio::time::timeout(timeout, tls.connect(stream))
+            .await
+            .map_err(|_| ClientError::Timeout(timeout))??;
+        Self::start(stream, timeout).await
+    }
+
+    /// Starts the reader and writer tasks on a connected stream and performs the `Hello` handshake
+    async fn start(stream: Box<dyn Transport>, timeout: Duration) -> Result<Self, ClientError> {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let pending = Arc::new(Pending {
+            waiters: Mutex::new(Some(HashMap::new())),
+        });
+        let (outbox, requests) = mpsc::channel(OUTBOX_SIZE);
+        let (pushed, pushes) = mpsc::channel(PUSH_BUFFER_SIZE);
+
+        let mut client = Client {
+            outbox: Some(outbox),
+            reader: tokio::spawn(read_responses(read_half, pending.clone(), pushed)),
+            writer: Some(tokio::spawn(write_requests(write_half, requests, pending.clone()))),
+            pending,
+            next_request_id: AtomicU64::new(1), // 0 means "no id" to the server
+            timeout,
+            server_info: HelloAck::default(),
+            pushes: Some(pushes),
+        };
+
+        let hello = client_message::Message::Hello(client_hello(MAX_RESPONSE_SIZE));
+        client.server_info = match client.request(hello).await? {
+            server_message::Message::HelloAck(ack) => ack,
+            other => return Err(ClientError::UnexpectedResponse(Some(other))),
+        };
+        info!(
+            "Server speaks protocol version {}, features {:?}",
+            client.server_info.protocol_version, client.server_info.features
+        );
+        Ok(client)
+    }
+
+    /// The server's answer to the handshake: protocol version, frame limit, message types and features
+    pub fn server_info(&self) -> &HelloAck {
+        &self.server_info
+    }
+
+    /// Takes the receiver of the messages the server pushes; `None` once taken.
+    ///
+    /// Up to 256 pushes are buffered; while the buffer is full further ones are dropped.
+    pub fn take_pushes(&mut self) -> Option<mpsc::Receiver<ServerMessage>> {
+        self.pushes.take()
+    }
+
+    /// The timeout applied to each call
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Whether the connection is still usable
+    pub fn is_connected(&self) -> bool {
+        self.pending.is_open()
+    }
+
+    /// Authenticates with a bearer token, returning the identity the server now knows the client as.
+    ///
+    /// A refused token fails with an `UNAUTHENTICATED` [`ClientError::Server`] and the server closes the connection.
+    pub async fn authenticate(&self, token: &str) -> Result<String, ClientError> {
+        self.send_credentials(authenticate::Credentials::Token(token.to_string())).await
+    }
+
+    /// Authenticates by signing a challenge from the server with the secret of `key_id`,
+    /// so the secret itself is never sent
+    pub async fn authenticate_hmac(&self, key_id: &str, secret: &[u8]) -> Result<String, ClientError> {
+        let message = client_message::Message::Authenticate(Authenticate {
+            credentials: Some(authenticate::Credentials::ChallengeFor(key_id.to_string())),
+        });
+        let nonce = match self.request(message).await? {
+            server_message::Message::AuthChallenge(challenge) => challenge.nonce,
+            other => return Err(ClientError::UnexpectedResponse(Some(other))),
+        };
+        self.send_credentials(authenticate::Credentials::Hmac(HmacSignature {
+            key_id: key_id.to_string(),
+            signature: sign_challenge(secret, &nonce),
+        }))
+        .await
+    }
+
+    async fn send_credentials(&self, credentials: authenticate::Credentials) -> Result<String, ClientError> {
+        let message = client_message::Message::Authenticate(Authenticate {
+            credentials: Some(credentials),
+        });
+        match self.request(message).await? {
+            server_message::Message::AuthResult(result) => Ok(result.identity),
+            other => Err(ClientError::UnexpectedResponse(Some(other))),
+        }
+    }
+
+    /// Echoes `content` through the server
+    pub async fn echo(&self, content: &str) -> Result<String, ClientError> {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        });
+        match self.request(message).await? {
+            server_message::Message::EchoMessage(echo) => Ok(echo.content),
+            other => Err(ClientError::UnexpectedResponse(Some(other))),
+        }
+    }
+
+    /// Adds `a` and `b` on the server; the response tells how an overflow was handled
+    pub async fn add(&self, a: i32, b: i32) -> Result<AddResponse, ClientError> {
+        let message = client_message::Message::AddRequest(AddRequest { a, b });
+        match self.request(message).await? {
+            server_message::Message::AddResponse(add_response) => Ok(add_response),
+            other => Err(ClientError::UnexpectedResponse(Some(other))),
+        }
+    }
+
+    /// Subscribes to the topics matching `filter`, returning how many retained
+    /// publications were pushed; publications arrive through `take_pushes`
+    pub async fn subscribe(&self, filter: &str) -> Result<u32, ClientError> {
+        let message = client_message::Message::Subscribe(Subscribe {
+            filter: filter.to_string(),
+        });
+        Ok(self.topic_request(message).await?.delivered)
+    }
+
+    /// Stops receiving the topics matching `filter`; other filters still apply
+    pub async fn unsubscribe(&self, filter: &str) -> Result<(), ClientError> {
+        let message = client_message::Message::Unsubscribe(Unsubscribe {
+            filter: filter.to_string(),
+        });
+        self.topic_request(message).await?;
+        Ok(())
+    }
+
+    /// Publishes `payload` on `topic`, returning how many subscribers it was queued for
+    pub async fn publish(&self, topic: &str, payload: &[u8], retain: bool) -> Result<u32, ClientError> {
+        let message = client_message::Message::Publish(Publish {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            retain,
+        });
+        Ok(self.topic_request(message).await?.delivered)
+    }
+
+    async fn topic_request(&self, message: client_message::Message) -> Result<TopicAck, ClientError> {
+        match self.request(message).await? {
+            server_message::Message::TopicAck(ack) => Ok(ack),
+            other => Err(ClientError::UnexpectedResponse(Some(other))),
+        }
+    }
+
+    /// Sends `message` and waits for the response, within the client's timeout
+    pub async fn request(&self, message: client_message::Message) -> Result<server_message::Message, ClientError> {
+        self.request_with_timeout(message, self.timeout).await
+    }
+
+    /// Sends `message` and waits for the response, within `timeout`.
+    ///
+    /// An `ErrorResponse` from the server is returned as [`ClientError::Server`].
+    /// A call that times out leaves the connection open; its late response is discarded.
+    /// A request above the server's frame limit fails with [`ClientError::RequestTooLarge`] without being sent.
+    pub async fn request_with_timeout(
+        &self,
+        message: client_message::Message,
+        timeout: Duration,
+    ) -> Result<server_message::Message, ClientError> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let request = ClientMessage {
+            message: Some(message),
+            request_id,
+        };
+        // The server would refuse it without the request id, so the call could only time out
+        let max = usize::try_from(self.server_info.max_frame_size).unwrap_or(usize::MAX);
+        let len = request.encoded_len();
+        if max > 0 && len > max {
+            return Err(ClientError::RequestTooLarge { len, max });
+        }
+
+        let response = self.pending.insert(request_id).ok_or(ClientError::Disconnected)?;
+        let outbox = self.outbox.as_ref().ok_or(ClientError::Disconnected)?;
+        let payload = encode_frame(&request);
+
+        let exchange = async {
+            // Fails only once the writer has stopped
+            outbox.send(payload).await.map_err(|_| ClientError::Disconnected)?;
+            response.await.map_err(|_| ClientError::Disconnected)
+        };
+        let result = match tokio::time::timeout(timeout, exchange).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.pending.remove(request_id);
+                Err(ClientError::Timeout(timeout))
+            }
+        };
+
+        response_message(result?)
+    }
+
+    /// Closes the connection once the requests already sent have been written
+    pub async fn close(mut self) -> Result<(), ClientError> {
+        self.outbox.take(); // the writer shuts the socket down when its queue ends
+        if let Some(writer) = self.writer.take() {
+            match writer.await {
+                Ok(result) => result?,
+                Err(e) => return Err(io::Error::other(e).into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.pending.close();
+        self.reader.abort();
+        if let Some(writer) = &self.writer {
+            writer.abort();
+        }
+    }
+}
+
+/// Opens the TCP connection, within `timeout`
+async fn connect_tcp(addr: impl ToSocketAddrs, timeout: Duration) -> Result<TcpStream, ClientError> {
+    let stream = tokio::time::timeout(timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| ClientError::Timeout(timeout))??;
+    stream.set_nodelay(true)?;
+    info!("Connected to {}", stream.peer_addr()?);
+    Ok(stream)
+}
+
+/// Writes queued requests until the client closes or the socket fails
+async fn write_requests(
+    mut writer: WriteHalf<Box<dyn Transport>>,
+    mut requests: mpsc::Receiver<Vec<u8>>,
+    pending: Arc<Pending>,
+) -> io::Result<()> {
+    while let Some(payload) = requests.recv().await {
+        if let Err(e) = writer.write_all(&payload).await {
+            error!("Failed to send request: {}", e);
+            pending.close();
+            return Err(e);
+        }
+        debug!("Sent {} bytes", payload.len());
+    }
+    writer.shutdown().await
+}
+
+/// Reads responses and hands each one to the call waiting for its id, and pushed messages to `pushed`
+async fn read_responses(mut reader: ReadHalf<Box<dyn Transport>>, pending: Arc<Pending>, pushed: mpsc::Sender<ServerMessage>) {
+    let mut frames = FrameBuffer::new(MAX_RESPONSE_SIZE);
+    let mut buffer = vec![0u8; 4096];
+
+    let result: Result<(), ClientError> = async {
+        loop {
+            // Keep reading until a whole frame has been reassembled
+            while let Some(frame) = frames.next_frame()? {
+                let response = ServerMessage::decode(frame.as_slice())?;
+                if response.push {
+                    // Waiting for room would hold up the responses behind it
+                    if pushed.try_send(response).is_err() {
+                        warn!("Dropping pushed message: no room left for it");
+                    }
+                    continue;
+                }
+                match pending.remove(response.request_id) {
+                    // The call may have given up already, then the response is dropped
+                    Some(waiter) => {
+                        let _ = waiter.send(response);
+                    }
+                    None => match response.message {
+                        Some(server_message::Message::ErrorResponse(e)) => {
+                            error!("Server error for request {}: {}", response.request_id, e.message)
+                        }
+                        _ => warn!("Discarding response to request {}", response.request_id),
+                    },
+                }
+            }
+
+            let bytes_read = reader.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                info!("Server disconnected.");
+                return Ok(());
+            }
+            frames.extend(&buffer[..bytes_read]);
+        }
+    }
+    .await;
+
+    if let Err(e) = result {
+        error!("Closing connection: {}", e);
+    }
+    pending.close();
+}
@@ -0,0 +1,735 @@
+use crate::handler::{MessageKind, OverflowPolicy};
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    env, fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Largest frame the server accepts unless configured otherwise
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4096;
+
+/// Requests of one connection handled at once unless configured otherwise
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+
+/// Pushed messages queued for one connection unless configured otherwise
+pub const DEFAULT_PUSH_QUEUE_SIZE: usize = 64;
+
+/// Topics with a retained publication kept at once unless configured otherwise
+pub const DEFAULT_MAX_RETAINED_TOPICS: usize = 10_000;
+
+/// Time a client gets to complete the TLS handshake unless configured otherwise
+pub const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prefix of the environment variables read by [`ServerConfig::apply_env`]
+pub const ENV_PREFIX: &str = "SERVER_";
+
+/// Tunables of a [`Server`](crate::server::Server).
+///
+/// Loaded from a TOML file, from `SERVER_*` environment variables, or built in
+/// code with [`ServerBuilder`](crate::server::ServerBuilder). Keys missing from
+/// the file keep their default value.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the listener binds to, e.g. `0.0.0.0:8080`
+    pub bind_addr: String,
+
+    /// Largest frame payload accepted from a client, in bytes
+    pub max_frame_size: usize,
+
+    /// What happens to a connection that announces a frame above `max_frame_size`
+    pub oversize_frame_policy: OversizeFramePolicy,
+
+    /// Connections served at once. `None` is unlimited
+    pub max_connections: Option<usize>,
+
+    /// What happens to clients arriving while `max_connections` are open
+    pub connection_limit_policy: ConnectionLimitPolicy,
+
+    /// Connections served at once for one client IP address; further ones are refused. `None` is unlimited
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Requests with an id that one connection may have in progress at once; reading pauses at the limit
+    pub max_in_flight: usize,
+
+    /// Pushed messages queued for one connection before the slow consumer policy applies
+    pub push_queue_size: usize,
+
+    /// What happens to a connection whose push queue is full
+    pub slow_consumer_policy: SlowConsumerPolicy,
+
+    /// Topics with a retained publication kept at once; retaining on further topics is refused
+    pub max_retained_topics: usize,
+
+    /// Closes connections whose first frame is not a `Hello`
+    pub require_handshake: bool,
+
+    /// Closes a connection that sends nothing for this long
+    #[serde(rename = "idle_timeout_ms", deserialize_with = "de_millis")]
+    pub idle_timeout: Option<Duration>,
+
+    /// Closes a connection that takes longer than this to finish sending a frame, counted from its first byte
+    #[serde(rename = "read_timeout_ms", deserialize_with = "de_millis")]
+    pub read_timeout: Option<Duration>,
+
+    /// Closes a connection whose response cannot be written within this time
+    #[serde(rename = "write_timeout_ms", deserialize_with = "de_millis")]
+    pub write_timeout: Option<Duration>,
+
+    /// Sends a `TIMED_OUT` error frame before closing a connection on an idle or read timeout
+    pub send_timeout_error: bool,
+
+    /// How long shutdown waits for open connections to finish before aborting them
+    #[serde(rename = "drain_timeout_ms", deserialize_with = "de_duration")]
+    pub drain_timeout: Duration,
+
+    /// How `AddRequest` sums outside the `i32` range are answered
+    pub overflow_policy: OverflowPolicy,
+
+    /// Verbosity of the logger installed by the server binary
+    pub log_level: LevelFilter,
+
+    /// Output format of the logger installed by the server binary
+    pub log_format: LogFormat,
+
+    /// Socket options of the listening socket
+    pub listener: ListenerConfig,
+
+    /// Request budgets per connection and per client address
+    pub rate_limit: RateLimitConfig,
+
+    /// Which connection identities may send which message kinds
+    pub acl: AclConfig,
+
+    /// Client addresses allowed to connect; can be replaced at runtime with `Server::set_ip_filter`
+    pub ip_filter: IpFilterConfig,
+
+    /// Address of the HTTP listener serving Prometheus metrics at `/metrics`. `None` disables it
+    pub metrics_addr: Option<String>,
+
+    /// Serves every connection over TLS. `None` serves plain TCP
+    pub tls: Option<TlsConfig>,
+
+    /// Token file of a [`StaticTokenAuthenticator`](crate::auth::StaticTokenAuthenticator); when set, clients
+    /// without a client certificate must send an `Authenticate` before any other request
+    pub auth_token_file: Option<PathBuf>,
+}
+
+/// What the server does with a frame whose declared length exceeds the limit.
+///
+/// Either way the client is sent a `FRAME_TOO_LARGE` error response first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizeFramePolicy {
+    /// Close the connection
+    #[default]
+    Close,
+    /// Skip the frame's bytes without buffering them and keep serving the connection
+    Skip,
+}
+
+impl FromStr for OversizeFramePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "close" => Ok(OversizeFramePolicy::Close),
+            "skip" => Ok(OversizeFramePolicy::Skip),
+            _ => Err("expected one of close, skip".to_string()),
+        }
+    }
+}
+
+/// What the server does when a message is pushed to a connection whose queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowConsumerPolicy {
+    /// Drop the pushed message and keep the connection
+    #[default]
+    Drop,
+    /// Close the connection
+    Disconnect,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop" => Ok(SlowConsumerPolicy::Drop),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err("expected one of drop, disconnect".to_string()),
+        }
+    }
+}
+
+/// How the server binary writes its log lines
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines, prefixed with the connection and request spans
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the enclosing spans, for log pipelines
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected one of text, json".to_string()),
+        }
+    }
+}
+
+/// What the server does with a client that arrives while it serves `max_connections`.
+///
+/// A client over `max_connections_per_ip` is dropped under `Drop` and rejected otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionLimitPolicy {
+    /// Leave the client in the listen backlog until a connection closes
+    #[default]
+    Queue,
+    /// Accept the client, send a `TOO_MANY_CONNECTIONS` error response and close
+    Reject,
+    /// Accept the client and close the connection straight away
+    Drop,
+}
+
+impl FromStr for ConnectionLimitPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "queue" => Ok(ConnectionLimitPolicy::Queue),
+            "reject" => Ok(ConnectionLimitPolicy::Reject),
+            "drop" => Ok(ConnectionLimitPolicy::Drop),
+            _ => Err("expected one of queue, reject, drop".to_string()),
+        }
+    }
+}
+
+/// Options applied to the listening socket and to accepted connections
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Length of the kernel's queue of not yet accepted connections
+    pub backlog: u32,
+
+    /// Sets `SO_REUSEADDR` so the server can restart while old sockets linger in `TIME_WAIT`
+    pub reuse_address: bool,
+
+    /// Sets `TCP_NODELAY` on accepted connections so small responses are not delayed
+    pub nodelay: bool,
+}
+
+/// Certificate and key the server presents to TLS clients, both PEM files
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificate chain, the server's own certificate first
+    pub cert_path: PathBuf,
+
+    /// Private key of the certificate, in PKCS#8, PKCS#1 or SEC1 form
+    pub key_path: PathBuf,
+
+    /// CA bundle client certificates must chain to. When set, clients without a valid certificate are refused
+    #[serde(default)]
+    pub client_ca_path: Option<PathBuf>,
+
+    /// Closes a connection that has not completed the TLS handshake within this time
+    #[serde(
+        rename = "handshake_timeout_ms",
+        default = "default_tls_handshake_timeout",
+        deserialize_with = "de_duration"
+    )]
+    pub handshake_timeout: Duration,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: None,
+            handshake_timeout: DEFAULT_TLS_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Requires every client to present a certificate issued by a CA in the PEM bundle at `ca_path`
+    pub fn require_client_cert(mut self, ca_path: impl Into<PathBuf>) -> Self {
+        self.client_ca_path = Some(ca_path.into());
+        self
+    }
+}
+
+fn default_tls_handshake_timeout() -> Duration {
+    DEFAULT_TLS_HANDSHAKE_TIMEOUT
+}
+
+/// Token-bucket limits on requests; a request over budget is answered with `RATE_LIMITED`.
+///
+/// The defaults apply to every message kind without an entry in `messages`.
+/// Kinds sharing the defaults also share their budget, while a kind with its own
+/// limit is counted separately.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Budget of each connection. `None` is unlimited
+    pub per_connection: Option<RateLimit>,
+
+    /// Budget shared by all connections from one IP address. `None` is unlimited
+    pub per_ip: Option<RateLimit>,
+
+    /// Limits for single message kinds, overriding the defaults above
+    pub messages: HashMap<MessageKind, MessageRateLimit>,
+}
+
+/// Access control list: the identities allowed to send each message kind.
+///
+/// A pattern is an identity, a prefix ending in `*` such as `sensor-*`, or `*`
+/// alone, which matches every connection, identified or not. Kinds without an
+/// entry in `messages` follow `default`. A request that is not allowed is
+/// answered with `PERMISSION_DENIED` without reaching its handler.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    /// Whether kinds without a rule are open to every connection or closed to all
+    pub default: AclDefault,
+
+    /// Identity patterns allowed to send each message kind
+    pub messages: HashMap<MessageKind, Vec<String>>,
+}
+
+impl AclConfig {
+    /// Restricts `kind` to the identities matching `patterns`
+    pub fn allow(mut self, kind: MessageKind, patterns: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.messages.insert(kind, patterns.into_iter().map(Into::into).collect());
+        self
+    }
+}
+
+/// What the ACL does with message kinds it has no rule for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclDefault {
+    /// Every connection may send them
+    #[default]
+    Allow,
+    /// No connection may send them
+    Deny,
+}
+
+impl FromStr for AclDefault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "allow" => Ok(AclDefault::Allow),
+            "deny" => Ok(AclDefault::Deny),
+            _ => Err("expected one of allow, deny".to_string()),
+        }
+    }
+}
+
+/// Client addresses the server accepts connections from, checked as soon as a connection is accepted.
+///
+/// A client inside a `deny` block is refused even if an `allow` block contains it too.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpFilterConfig {
+    /// Blocks clients must connect from; empty allows every address that is not denied
+    pub allow: Vec<IpNet>,
+
+    /// Blocks whose clients are refused
+    pub deny: Vec<IpNet>,
+}
+
+/// A block of IP addresses in CIDR notation, e.g. `10.20.0.0/16`; a bare address is a block of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Fails if `prefix_len` is longer than the address
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, String> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return Err(format!("prefix length {} is longer than {} bits", prefix_len, max));
+        }
+        Ok(IpNet { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("invalid address {:?}: {}", addr, e))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|e| format!("invalid prefix length: {}", e))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        IpNet::new(addr, prefix_len)
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Limits for one message kind; an unset limit falls back to the default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageRateLimit {
+    pub per_connection: Option<RateLimit>,
+    pub per_ip: Option<RateLimit>,
+}
+
+/// A token bucket: `burst` requests at once, refilled at `rate` requests per second
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "RawRateLimit")]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Fails unless `rate` is a positive, finite number and `burst` lets at least one request through
+    pub fn new(rate: f64, burst: u32) -> Result<Self, String> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err("rate must be a positive number of requests per second".to_string());
+        }
+        if burst == 0 {
+            return Err("burst must be at least 1".to_string());
+        }
+        Ok(RateLimit { rate, burst })
+    }
+}
+
+/// A `RateLimit` as written in TOML, before it is checked
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRateLimit {
+    rate: f64,
+    burst: u32,
+}
+
+impl TryFrom<RawRateLimit> for RateLimit {
+    type Error = String;
+
+    fn try_from(raw: RawRateLimit) -> Result<Self, Self::Error> {
+        RateLimit::new(raw.rate, raw.burst)
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// Parses `RATE` or `RATE:BURST`; the burst defaults to one second's worth of requests
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rate, burst) = match s.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let rate: f64 = rate.trim().parse().map_err(|e| format!("invalid rate: {}", e))?;
+        let burst = match burst {
+            Some(burst) => burst.trim().parse().map_err(|e| format!("invalid burst: {}", e))?,
+            None => (rate.ceil() as u32).max(1),
+        };
+        RateLimit::new(rate, burst)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_addr: "127.0.0.1:8080".to_string(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            oversize_frame_policy: OversizeFramePolicy::default(),
+            max_connections: None,
+            connection_limit_policy: ConnectionLimitPolicy::default(),
+            max_connections_per_ip: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            push_queue_size: DEFAULT_PUSH_QUEUE_SIZE,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+            max_retained_topics: DEFAULT_MAX_RETAINED_TOPICS,
+            require_handshake: false,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            send_timeout_error: false,
+            drain_timeout: Duration::from_secs(5),
+            overflow_policy: OverflowPolicy::default(),
+            log_level: LevelFilter::Info,
+            log_format: LogFormat::default(),
+            listener: ListenerConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            acl: AclConfig::default(),
+            ip_filter: IpFilterConfig::default(),
+            metrics_addr: None,
+            tls: None,
+            auth_token_file: None,
+        }
+    }
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            backlog: 1024,
+            reuse_address: true,
+            nodelay: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Parses a configuration from TOML text
+    pub fn from_toml_str(text: &str) -> io::Result<Self> {
+        let config: ServerConfig = toml::from_str(text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid server configuration: {}", e),
+            )
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings no server can run with, such as a connection limit of `0`:
+    /// under the queue policy it would stop accepting altogether
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidData, message.to_string()));
+        if self.max_connections == Some(0) {
+            return invalid("max_connections must be at least 1; omit it for no limit");
+        }
+        if self.max_connections_per_ip == Some(0) {
+            return invalid("max_connections_per_ip must be at least 1; omit it for no limit");
+        }
+        Ok(())
+    }
+
+    /// Reads and parses a TOML configuration file
+    pub fn from_toml_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Failed to read {}: {}", path.display(), e))
+        })?;
+        Self::from_toml_str(&text)
+    }
+
+    /// Builds a configuration from the defaults and the `SERVER_*` environment variables
+    pub fn from_env() -> io::Result<Self> {
+        let mut config = ServerConfig::default();
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Overrides fields with the `SERVER_*` environment variables that are set.
+    ///
+    /// Timeouts are given in milliseconds, and `0` or `none` clears an optional limit.
+    pub fn apply_env(&mut self) -> io::Result<()> {
+        self.apply_vars(|name| env::var(format!("{}{}", ENV_PREFIX, name)).ok())
+    }
+
+    /// Overrides fields with the variables `lookup` returns, keyed without the prefix
+    fn apply_vars(&mut self, lookup: impl Fn(&str) -> Option<String>) -> io::Result<()> {
+        if let Some(value) = lookup("BIND_ADDR") {
+            self.bind_addr = value;
+        }
+        if let Some(value) = lookup("MAX_FRAME_SIZE") {
+            self.max_frame_size = parse_var("MAX_FRAME_SIZE", &value)?;
+        }
+        if let Some(value) = lookup("OVERSIZE_FRAME_POLICY") {
+            self.oversize_frame_policy = parse_var("OVERSIZE_FRAME_POLICY", &value)?;
+        }
+        if let Some(value) = lookup("MAX_CONNECTIONS") {
+            self.max_connections = parse_optional_var("MAX_CONNECTIONS", &value)?;
+        }
+        if let Some(value) = lookup("CONNECTION_LIMIT_POLICY") {
+            self.connection_limit_policy = parse_var("CONNECTION_LIMIT_POLICY", &value)?;
+        }
+        if let Some(value) = lookup("MAX_CONNECTIONS_PER_IP") {
+            self.max_connections_per_ip = parse_optional_var("MAX_CONNECTIONS_PER_IP", &value)?;
+        }
+        if let Some(value) = lookup("MAX_IN_FLIGHT") {
+            self.max_in_flight = parse_var("MAX_IN_FLIGHT", &value)?;
+        }
+        if let Some(value) = lookup("PUSH_QUEUE_SIZE") {
+            self.push_queue_size = parse_var("PUSH_QUEUE_SIZE", &value)?;
+        }
+        if let Some(value) = lookup("SLOW_CONSUMER_POLICY") {
+            self.slow_consumer_policy = parse_var("SLOW_CONSUMER_POLICY", &value)?;
+        }
+        if let Some(value) = lookup("MAX_RETAINED_TOPICS") {
+            self.max_retained_topics = parse_var("MAX_RETAINED_TOPICS", &value)?;
+        }
+        if let Some(value) = lookup("REQUIRE_HANDSHAKE") {
+            self.require_handshake = parse_var("REQUIRE_HANDSHAKE", &value)?;
+        }
+        if let Some(value) = lookup("IDLE_TIMEOUT_MS") {
+            self.idle_timeout = parse_optional_var("IDLE_TIMEOUT_MS", &value)?.map(Duration::from_millis);
+        }
+        if let Some(value) = lookup("READ_TIMEOUT_MS") {
+            self.read_timeout = parse_optional_var("READ_TIMEOUT_MS", &value)?.map(Duration::from_millis);
+        }
+        if let Some(value) = lookup("WRITE_TIMEOUT_MS") {
+            self.write_timeout = parse_optional_var("WRITE_TIMEOUT_MS", &value)?.map(Duration::from_millis);
+        }
+        if let Some(value) = lookup("SEND_TIMEOUT_ERROR") {
+            self.send_timeout_error = parse_var("SEND_TIMEOUT_ERROR", &value)?;
+        }
+        if let Some(value) = lookup("DRAIN_TIMEOUT_MS") {
+            self.drain_timeout = Duration::from_millis(parse_var("DRAIN_TIMEOUT_MS", &value)?);
+        }
+        if let Some(value) = lookup("OVERFLOW_POLICY") {
+            self.overflow_policy = parse_var("OVERFLOW_POLICY", &value)?;
+        }
+        if let Some(value) = lookup("LOG_LEVEL") {
+            self.log_level = parse_var("LOG_LEVEL", &value)?;
+        }
+        if let Some(value) = lookup("LOG_FORMAT") {
+            self.log_format = parse_var("LOG_FORMAT", &value)?;
+        }
+        if let Some(value) = lookup("RATE_LIMIT_PER_CONNECTION") {
+            self.rate_limit.per_connection = parse_limit_var("RATE_LIMIT_PER_CONNECTION", &value)?;
+        }
+        if let Some(value) = lookup("RATE_LIMIT_PER_IP") {
+            self.rate_limit.per_ip = parse_limit_var("RATE_LIMIT_PER_IP", &value)?;
+        }
+        if let Some(value) = lookup("ACL_DEFAULT") {
+            self.acl.default = parse_var("ACL_DEFAULT", &value)?;
+        }
+        if let Some(value) = lookup("IP_ALLOW") {
+            self.ip_filter.allow = parse_list_var("IP_ALLOW", &value)?;
+        }
+        if let Some(value) = lookup("IP_DENY") {
+            self.ip_filter.deny = parse_list_var("IP_DENY", &value)?;
+        }
+        if let Some(value) = lookup("METRICS_ADDR") {
+            self.metrics_addr = parse_optional_var("METRICS_ADDR", &value)?;
+        }
+        if let Some(value) = lookup("TLS_CERT_PATH") {
+            self.tls_mut().cert_path = value.into();
+        }
+        if let Some(value) = lookup("TLS_KEY_PATH") {
+            self.tls_mut().key_path = value.into();
+        }
+        if let Some(value) = lookup("TLS_CLIENT_CA_PATH") {
+            self.tls_mut().client_ca_path = parse_optional_var::<String>("TLS_CLIENT_CA_PATH", &value)?.map(PathBuf::from);
+        }
+        if let Some(value) = lookup("AUTH_TOKEN_FILE") {
+            self.auth_token_file = parse_optional_var::<String>("AUTH_TOKEN_FILE", &value)?.map(PathBuf::from);
+        }
+        if let Some(value) = lookup("LISTEN_BACKLOG") {
+            self.listener.backlog = parse_var("LISTEN_BACKLOG", &value)?;
+        }
+        if let Some(value) = lookup("REUSE_ADDRESS") {
+            self.listener.reuse_address = parse_var("REUSE_ADDRESS", &value)?;
+        }
+        if let Some(value) = lookup("TCP_NODELAY") {
+            self.listener.nodelay = parse_var("TCP_NODELAY", &value)?;
+        }
+        Ok(())
+    }
+
+    /// The TLS settings, enabled with empty paths if they were not set yet
+    fn tls_mut(&mut self) -> &mut TlsConfig {
+        self.tls.get_or_insert_with(|| TlsConfig::new(PathBuf::new(), PathBuf::new()))
+    }
+}
+
+/// Parses one environment variable, naming it in the error
+fn parse_var<T: FromStr>(name: &str, value: &str) -> io::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value.trim().parse().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid value {:?} for {}{}: {}", value, ENV_PREFIX, name, e),
+        )
+    })
+}
+
+/// Parses a limit where `0` and `none` mean "no limit"
+fn parse_optional_var<T: FromStr + Default + PartialEq>(name: &str, value: &str) -> io::Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    if value.trim().eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    let parsed: T = parse_var(name, value)?;
+    Ok(if parsed == T::default() { None } else { Some(parsed) })
+}
+
+/// Parses a comma-separated list, where an empty value is an empty list
+fn parse_list_var<T: FromStr>(name: &str, value: &str) -> io::Result<Vec<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .split(',')
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| parse_var(name, item))
+        .collect()
+}
+
+/// Parses a `RATE[:BURST]` rate limit, where `none` removes the limit
+fn parse_limit_var(name: &str, value: &str) -> io::Result<Option<RateLimit>> {
+    if value.trim().eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    parse_var(name, value).map(Some)
+}
+
+/// Deserializes an optional number of milliseconds into a `Duration`
+fn de_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    Ok(Option::<u64>::deserialize(deserializer)?
+        .filter(|&millis| millis > 0)
+        .map(Duration::from_millis))
+}
+
+/// Deserializes a number of milliseconds into a `Duration`
+fn de_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
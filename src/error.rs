@@ -0,0 +1,93 @@
+use crate::framing::FrameError;
+use crate::message::{server_message, ErrorCode, ServerMessage};
+use std::{fmt, io, time::Duration};
+
+/// Largest response frame the clients accept
+pub(crate) const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Pushed messages a client buffers until they are taken; beyond it pushes are dropped
+pub(crate) const PUSH_BUFFER_SIZE: usize = 256;
+
+/// Everything that can go wrong with a client call
+#[derive(Debug)]
+pub enum ClientError {
+    /// The socket failed
+    Io(io::Error),
+    /// The call did not complete in time. The async `client::Client` keeps the connection
+    /// open and drops the late response; the `blocking::Client` closes the connection
+    Timeout(Duration),
+    /// The server closed the connection, or an earlier failure closed it
+    Disconnected,
+    /// The response stream could not be split into frames
+    Frame(FrameError),
+    /// A response frame did not decode as a `ServerMessage`
+    Decode(prost::DecodeError),
+    /// The request is larger than the frame limit the server announced in its `HelloAck`; it was not sent
+    RequestTooLarge { len: usize, max: usize },
+    /// The server answered with an `ErrorResponse`
+    Server { code: ErrorCode, message: String },
+    /// The server answered with a message of the wrong type
+    UnexpectedResponse(Option<server_message::Message>),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "I/O error: {}", e),
+            ClientError::Timeout(limit) => write!(f, "No response within {} ms", limit.as_millis()),
+            ClientError::Disconnected => write!(f, "Not connected to the server"),
+            ClientError::Frame(e) => write!(f, "Invalid response frame: {}", e),
+            ClientError::Decode(e) => write!(f, "Failed to decode ServerMessage: {}", e),
+            ClientError::RequestTooLarge { len, max } => {
+                write!(f, "Request of {} bytes exceeds the server's maximum frame size {}", len, max)
+            }
+            ClientError::Server { code, message } => {
+                write!(f, "Server error {}: {}", code.as_str_name(), message)
+            }
+            ClientError::UnexpectedResponse(message) => {
+                write!(f, "Unexpected response: {:?}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            ClientError::Frame(e) => Some(e),
+            ClientError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<FrameError> for ClientError {
+    fn from(e: FrameError) -> Self {
+        ClientError::Frame(e)
+    }
+}
+
+impl From<prost::DecodeError> for ClientError {
+    fn from(e: prost::DecodeError) -> Self {
+        ClientError::Decode(e)
+    }
+}
+
+/// Unwraps the message of a response, turning an `ErrorResponse` into [`ClientError::Server`]
+pub(crate) fn response_message(response: ServerMessage) -> Result<server_message::Message, ClientError> {
+    match response.message {
+        Some(server_message::Message::ErrorResponse(error)) => Err(ClientError::Server {
+            code: error.code(),
+            message: error.message,
+        }),
+        Some(message) => Ok(message),
+        None => Err(ClientError::UnexpectedResponse(None)),
+    }
+}
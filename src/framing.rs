@@ -0,0 +1,136 @@
+use prost::Message;
+use std::{fmt, io};
+
+/// Longest varint a length prefix may use (the encoding of a `u64`).
+const MAX_PREFIX_LEN: usize = 10;
+
+/// Encodes a message behind a varint length prefix, the same layout as prost's
+/// `encode_length_delimited`, so it can be written to the socket as one frame.
+pub fn encode_frame<M: Message>(message: &M) -> Vec<u8> {
+    message.encode_length_delimited_to_vec()
+}
+
+/// Reasons the byte stream cannot be split into frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The length prefix declares a frame larger than the configured limit.
+    TooLarge { len: usize, max: usize },
+    /// The length prefix is not a valid varint.
+    InvalidPrefix,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => write!(
+                f,
+                "Frame of {} bytes exceeds the maximum allowed size {}",
+                len, max
+            ),
+            FrameError::InvalidPrefix => write!(f, "Invalid frame length prefix"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Reassembly buffer that turns the raw TCP byte stream back into frames.
+///
+/// A single `read` can return several frames glued together or only part of
+/// one, so bytes are accumulated here and complete frames are taken out with
+/// [`FrameBuffer::next_frame`].
+pub struct FrameBuffer {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+    discarding: usize, // Bytes of a skipped frame that have not arrived yet
+}
+
+impl FrameBuffer {
+    /// Creates an empty buffer that rejects frames longer than `max_frame_size`.
+    pub fn new(max_frame_size: usize) -> Self {
+        FrameBuffer {
+            buffer: Vec::new(),
+            max_frame_size,
+            discarding: 0,
+        }
+    }
+
+    /// Appends bytes read from the socket, dropping those that belong to a skipped frame.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        let skipped = self.discarding.min(bytes.len());
+        self.discarding -= skipped;
+        self.buffer.extend_from_slice(&bytes[skipped..]);
+    }
+
+    /// Number of buffered bytes that do not yet form a complete frame.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Whether the buffer sits on a frame boundary: nothing buffered and nothing left to skip.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty() && self.discarding == 0
+    }
+
+    /// Takes the next complete frame payload out of the buffer.
+    ///
+    /// Returns `Ok(None)` while more bytes are needed. After
+    /// [`FrameError::TooLarge`] the frame can be dropped with
+    /// [`FrameBuffer::discard_frame`]; after [`FrameError::InvalidPrefix`] the
+    /// stream cannot be resynchronised and the connection should be closed.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let (frame_len, prefix_len) = match decode_prefix(&self.buffer)? {
+            Some(prefix) => prefix,
+            None => return Ok(None),
+        };
+
+        if frame_len > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                len: frame_len,
+                max: self.max_frame_size,
+            });
+        }
+
+        if self.buffer.len() < prefix_len + frame_len {
+            return Ok(None); // The rest of the frame has not arrived yet
+        }
+
+        let frame = self.buffer[prefix_len..prefix_len + frame_len].to_vec();
+        self.buffer.drain(..prefix_len + frame_len);
+        Ok(Some(frame))
+    }
+
+    /// Drops the frame at the front of the buffer, including the part still to arrive,
+    /// so the frames after an oversized one can be read. Returns the frame's length.
+    pub fn discard_frame(&mut self) -> Option<usize> {
+        let (frame_len, prefix_len) = decode_prefix(&self.buffer).ok()??;
+        let buffered = (self.buffer.len() - prefix_len).min(frame_len);
+        self.buffer.drain(..prefix_len + buffered);
+        self.discarding = frame_len - buffered;
+        Some(frame_len)
+    }
+}
+
+/// Decodes the varint length prefix at the start of `buf`, returning the frame
+/// length and the number of bytes the prefix occupies.
+fn decode_prefix(buf: &[u8]) -> Result<Option<(usize, usize)>, FrameError> {
+    let mut value: u64 = 0;
+    for (i, byte) in buf.iter().take(MAX_PREFIX_LEN).enumerate() {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            let frame_len = usize::try_from(value).map_err(|_| FrameError::InvalidPrefix)?;
+            return Ok(Some((frame_len, i + 1)));
+        }
+    }
+
+    if buf.len() >= MAX_PREFIX_LEN {
+        return Err(FrameError::InvalidPrefix);
+    }
+    Ok(None) // The prefix itself is still incomplete
+}
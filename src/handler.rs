@@ -0,0 +1,308 @@
+use crate::message::{
+    client_message, server_message, AddOutcome, AddResponse, ErrorCode, ErrorResponse, ServerMessage,
+};
+use async_trait::async_trait;
+use tracing::{error, info};
+use serde::Deserialize;
+use std::{collections::HashMap, fmt, net::SocketAddr, str::FromStr, sync::Arc};
+
+/// Identifies a variant of the `ClientMessage` oneof; the key of the handler registry.
+///
+/// In configuration files a kind is written as its oneof field name, e.g. `add_request`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    EchoMessage,
+    AddRequest,
+    Subscribe,
+    Unsubscribe,
+    Publish,
+}
+
+impl MessageKind {
+    /// Every variant of the `ClientMessage` oneof
+    pub const ALL: [MessageKind; 5] = [
+        MessageKind::EchoMessage,
+        MessageKind::AddRequest,
+        MessageKind::Subscribe,
+        MessageKind::Unsubscribe,
+        MessageKind::Publish,
+    ];
+
+    /// Returns the kind of a decoded message, or `None` for the `Hello` and `Authenticate` the server answers itself
+    pub fn of(message: &client_message::Message) -> Option<Self> {
+        match message {
+            client_message::Message::EchoMessage(_) => Some(MessageKind::EchoMessage),
+            client_message::Message::AddRequest(_) => Some(MessageKind::AddRequest),
+            client_message::Message::Subscribe(_) => Some(MessageKind::Subscribe),
+            client_message::Message::Unsubscribe(_) => Some(MessageKind::Unsubscribe),
+            client_message::Message::Publish(_) => Some(MessageKind::Publish),
+            client_message::Message::Hello(_) | client_message::Message::Authenticate(_) => None,
+        }
+    }
+
+    /// The oneof field name from `messages.proto`, e.g. `add_request`
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageKind::EchoMessage => "echo_message",
+            MessageKind::AddRequest => "add_request",
+            MessageKind::Subscribe => "subscribe",
+            MessageKind::Unsubscribe => "unsubscribe",
+            MessageKind::Publish => "publish",
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Information about the connection a request arrived on
+#[derive(Debug, Clone)]
+pub struct ConnectionContext {
+    pub connection_id: u64,       // Unique per server, assigned in accept order
+    pub peer_addr: SocketAddr,    // Address of the remote client
+    pub identity: Option<String>, // Who the client's TLS certificate or Authenticate proved it is, if known
+}
+
+/// Error returned by a handler; it reaches the client as an `ErrorResponse`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandlerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl HandlerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        HandlerError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Converts the error into the `ServerMessage` sent back to the client
+    pub fn into_response(self) -> ServerMessage {
+        ServerMessage {
+            message: Some(server_message::Message::ErrorResponse(ErrorResponse {
+                code: self.code.into(),
+                message: self.message,
+                request_id: 0, // filled in by the server, which knows the request's id
+            })),
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code.as_str_name(), self.message)
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+/// Business logic for one kind of client message.
+///
+/// Handlers are registered on the server per `MessageKind` and called with the
+/// decoded message, so new services can be added without touching the socket loop.
+#[async_trait]
+pub trait Handler: Send + Sync {
+    async fn handle(
+        &self,
+        message: client_message::Message,
+        ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError>;
+}
+
+/// Maps each `MessageKind` to the handler that serves it
+#[derive(Clone, Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<MessageKind, Arc<dyn Handler>>,
+    builtin_add: bool, // AddRequest is served by the built-in AddHandler, which follows the server's overflow policy
+}
+
+impl HandlerRegistry {
+    /// Creates a registry with no handlers; every request is answered with `UNSUPPORTED_MESSAGE`
+    pub fn new() -> Self {
+        HandlerRegistry::default()
+    }
+
+    /// Creates a registry serving the built-in echo and add messages
+    pub fn with_defaults() -> Self {
+        let mut registry = HandlerRegistry::new();
+        registry.register(MessageKind::EchoMessage, EchoHandler);
+        registry.register(MessageKind::AddRequest, AddHandler::default());
+        registry.builtin_add = true;
+        registry
+    }
+
+    /// Registers `handler` for `kind`, returning the handler it replaces
+    pub fn register<H: Handler + 'static>(&mut self, kind: MessageKind, handler: H) -> Option<Arc<dyn Handler>> {
+        self.builtin_add &= kind != MessageKind::AddRequest;
+        self.handlers.insert(kind, Arc::new(handler))
+    }
+
+    /// Removes the handler for `kind`
+    pub fn unregister(&mut self, kind: MessageKind) -> Option<Arc<dyn Handler>> {
+        self.builtin_add &= kind != MessageKind::AddRequest;
+        self.handlers.remove(&kind)
+    }
+
+    /// Makes the built-in `AddHandler` follow `overflow_policy`; an `AddRequest` handler
+    /// registered in its place is left alone
+    pub(crate) fn apply_overflow_policy(&mut self, overflow_policy: OverflowPolicy) {
+        if self.builtin_add {
+            self.handlers.insert(MessageKind::AddRequest, Arc::new(AddHandler::new(overflow_policy)));
+        }
+    }
+
+    pub fn get(&self, kind: MessageKind) -> Option<&Arc<dyn Handler>> {
+        self.handlers.get(&kind)
+    }
+
+    /// The message kinds that currently have a handler
+    pub fn kinds(&self) -> Vec<MessageKind> {
+        MessageKind::ALL
+            .into_iter()
+            .filter(|kind| self.handlers.contains_key(kind))
+            .collect()
+    }
+
+    /// Routes `message` to its handler and turns any failure into an error response
+    pub async fn dispatch(&self, message: client_message::Message, ctx: &ConnectionContext) -> ServerMessage {
+        let Some(kind) = MessageKind::of(&message) else {
+            error!("Hello received after the first frame");
+            return HandlerError::new(ErrorCode::UnsupportedMessage, "Hello is only accepted as the first frame")
+                .into_response();
+        };
+        let Some(handler) = self.get(kind) else {
+            error!("No handler registered for {}", kind);
+            return HandlerError::new(
+                ErrorCode::UnsupportedMessage,
+                format!("No handler registered for {}", kind),
+            )
+            .into_response();
+        };
+
+        match handler.handle(message, ctx).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Handler for {} failed: {}", kind, e);
+                e.into_response()
+            }
+        }
+    }
+}
+
+/// Sends `EchoMessage`s back unchanged
+pub struct EchoHandler;
+
+#[async_trait]
+impl Handler for EchoHandler {
+    async fn handle(
+        &self,
+        message: client_message::Message,
+        _ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        let client_message::Message::EchoMessage(echo_message) = message else {
+            return Err(HandlerError::new(ErrorCode::UnsupportedMessage, "Expected EchoMessage"));
+        };
+        info!("Received EchoMessage: {}", echo_message.content);
+
+        // Echo back the message
+        Ok(ServerMessage {
+            message: Some(server_message::Message::EchoMessage(echo_message)),
+            ..Default::default()
+        })
+    }
+}
+
+/// What the server does when the sum of an `AddRequest` does not fit in an `i32`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Reply with an `ARITHMETIC_OVERFLOW` error response
+    #[default]
+    Error,
+    /// Clamp the result to `i32::MIN` / `i32::MAX`
+    Saturate,
+    /// Wrap around using two's complement arithmetic
+    Wrap,
+    /// Report the exact sum in `wide_result`; `result` holds the saturated value
+    Widen,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(OverflowPolicy::Error),
+            "saturate" => Ok(OverflowPolicy::Saturate),
+            "wrap" => Ok(OverflowPolicy::Wrap),
+            "widen" => Ok(OverflowPolicy::Widen),
+            _ => Err("expected one of error, saturate, wrap, widen".to_string()),
+        }
+    }
+}
+
+/// Answers `AddRequest`s, applying an `OverflowPolicy` to sums outside the `i32` range
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AddHandler {
+    overflow_policy: OverflowPolicy,
+}
+
+impl AddHandler {
+    pub fn new(overflow_policy: OverflowPolicy) -> Self {
+        AddHandler { overflow_policy }
+    }
+}
+
+#[async_trait]
+impl Handler for AddHandler {
+    async fn handle(
+        &self,
+        message: client_message::Message,
+        _ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        let client_message::Message::AddRequest(add_request) = message else {
+            return Err(HandlerError::new(ErrorCode::UnsupportedMessage, "Expected AddRequest"));
+        };
+        info!(
+            "Received AddRequest: a={}, b={}",
+            add_request.a, add_request.b
+        );
+
+        match add(add_request.a, add_request.b, self.overflow_policy) {
+            Some(add_response) => Ok(ServerMessage {
+                message: Some(server_message::Message::AddResponse(add_response)),
+                ..Default::default()
+            }),
+            None => Err(HandlerError::new(
+                ErrorCode::ArithmeticOverflow,
+                format!("{} + {} overflows a 32-bit integer", add_request.a, add_request.b),
+            )),
+        }
+    }
+}
+
+/// Adds `a` and `b`, returning `None` when the sum overflows under `OverflowPolicy::Error`
+fn add(a: i32, b: i32, policy: OverflowPolicy) -> Option<AddResponse> {
+    let wide_result = i64::from(a) + i64::from(b);
+    let (result, outcome) = match (a.checked_add(b), policy) {
+        (Some(result), _) => (result, AddOutcome::Exact),
+        (None, OverflowPolicy::Error) => return None,
+        (None, OverflowPolicy::Saturate) => (a.saturating_add(b), AddOutcome::Saturated),
+        (None, OverflowPolicy::Wrap) => (a.wrapping_add(b), AddOutcome::Wrapped),
+        (None, OverflowPolicy::Widen) => (a.saturating_add(b), AddOutcome::Widened),
+    };
+
+    Some(AddResponse {
+        result,
+        outcome: outcome.into(),
+        // only widening servers fill in the 64-bit sum, so clients can rely on it being exact
+        wide_result: if policy == OverflowPolicy::Widen { wide_result } else { 0 },
+    })
+}
@@ -0,0 +1,50 @@
+use crate::message::{Hello, HelloAck};
+
+/// Protocol version spoken by this crate
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version the server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Requests carrying a `request_id` may be answered out of order
+pub const FEATURE_PIPELINING: &str = "pipelining";
+
+/// Optional features the server can enable; unknown features requested by a client are ignored
+pub const SUPPORTED_FEATURES: &[&str] = &[FEATURE_PIPELINING];
+
+/// The `Hello` the clients of this crate send, asking for every supported feature
+pub fn client_hello(max_frame_size: usize) -> Hello {
+    Hello {
+        protocol_version: PROTOCOL_VERSION,
+        max_frame_size: u32::try_from(max_frame_size).unwrap_or(u32::MAX),
+        features: SUPPORTED_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+    }
+}
+
+/// Picks the protocol version and features for a connection, or explains why the client is incompatible
+pub fn negotiate(
+    hello: &Hello,
+    supported_messages: Vec<String>,
+    max_frame_size: usize,
+) -> Result<HelloAck, String> {
+    // A newer client may still speak our version, an older one must be at least the minimum
+    let protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "Protocol version {} is not supported; this server speaks versions {} to {}",
+            hello.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+
+    Ok(HelloAck {
+        protocol_version,
+        max_frame_size: u32::try_from(max_frame_size).unwrap_or(u32::MAX),
+        supported_messages,
+        features: hello
+            .features
+            .iter()
+            .filter(|feature| SUPPORTED_FEATURES.contains(&feature.as_str()))
+            .cloned()
+            .collect(),
+    })
+}
@@ -0,0 +1,36 @@
+use crate::config::{IpFilterConfig, IpNet};
+use std::net::IpAddr;
+
+impl IpFilterConfig {
+    /// Whether a client connecting from `ip` may be served
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
+
+impl IpNet {
+    /// Whether `ip` is inside the block; an IPv4 client on a dual-stack listener
+    /// arrives as an IPv4-mapped IPv6 address and is matched as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match self.addr() {
+            IpAddr::V4(net) => match ip.to_canonical() {
+                IpAddr::V4(ip) => {
+                    let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len())).unwrap_or(0);
+                    u32::from(net) & mask == u32::from(ip) & mask
+                }
+                IpAddr::V6(_) => false,
+            },
+            IpAddr::V6(net) => {
+                let ip = match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len())).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+        }
+    }
+}
@@ -1,8 +1,67 @@
 
 /// This module contains the server logic for handling client connections and requests.
+#[cfg(feature = "async")]
 pub mod server;
 
+/// This module contains the connection limits applied to accepted clients.
+#[cfg(feature = "async")]
+mod admission;
 
+/// This module contains the CIDR allow and deny checks applied on accept.
+#[cfg(feature = "async")]
+mod ip_filter;
+
+/// This module contains the registry of live connections behind `Server::connections`.
+#[cfg(feature = "async")]
+mod registry;
+
+/// This module contains topic matching and the publish/subscribe handler.
+#[cfg(feature = "async")]
+pub mod pubsub;
+
+/// This module contains the access control checks run before requests are dispatched.
+#[cfg(feature = "async")]
+mod acl;
+
+/// This module contains the token buckets that limit request rates.
+#[cfg(feature = "async")]
+mod rate_limit;
+
+/// This module contains the TLS setup shared by the server and the async client.
+#[cfg(feature = "async")]
+pub mod tls;
+
+/// This module contains the `Authenticator` trait and the token file authenticator.
+#[cfg(feature = "async")]
+pub mod auth;
+
+/// This module contains the counters the server keeps while it runs.
+#[cfg(feature = "async")]
+pub mod metrics;
+
+/// This module contains the server configuration and its TOML and environment loaders.
+#[cfg(feature = "async")]
+pub mod config;
+
+/// This module contains the asynchronous client library built on tokio.
+#[cfg(feature = "async")]
+pub mod client;
+
+/// This module contains the blocking client library built on `std::net`, usable without tokio.
+pub mod blocking;
+
+/// This module contains the error type shared by both clients.
+pub mod error;
+
+/// This module contains the length-delimited framing shared by the server and the clients.
+pub mod framing;
+
+/// This module contains the protocol version and the `Hello` negotiation.
+pub mod handshake;
+
+/// This module contains the `Handler` trait and the registry that dispatches requests to handlers.
+#[cfg(feature = "async")]
+pub mod handler;
 
 /// This module includes Protobuf-generated message structures.
 /// It is auto-generated during the build process.
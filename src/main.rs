@@ -0,0 +1,360 @@
+use clap::Parser;
+use embedded_recruitment_task::{
+    config::{
+        ConnectionLimitPolicy, IpNet, LogFormat, OversizeFramePolicy, RateLimit, ServerConfig, SlowConsumerPolicy, TlsConfig,
+    },
+    handler::OverflowPolicy,
+    server::Server,
+};
+use tracing::{error, info};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+use std::{path::PathBuf, process::ExitCode, sync::Arc, time::Duration};
+
+/// Exit code for a server that stopped because of an error while running
+const EXIT_RUNTIME_ERROR: u8 = 1;
+/// Exit code for an unreadable or invalid configuration (clap also uses 2 for bad flags)
+const EXIT_CONFIG_ERROR: u8 = 2;
+/// Exit code for a listener that could not be bound, or a TLS certificate or token file that could not be loaded
+const EXIT_BIND_ERROR: u8 = 3;
+
+/// Runs the echo/add protocol server.
+///
+/// Settings are taken from the defaults, then the `--config` file, then the
+/// `SERVER_*` environment variables, then the flags below; later sources win.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// TOML configuration file
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 0.0.0.0:8080
+    #[arg(short, long, value_name = "ADDR")]
+    bind: Option<String>,
+
+    /// Largest accepted frame, in bytes
+    #[arg(long, value_name = "BYTES")]
+    max_frame_size: Option<usize>,
+
+    /// What to do with a frame above the size limit: close or skip
+    #[arg(long, value_name = "POLICY")]
+    oversize_frame_policy: Option<OversizeFramePolicy>,
+
+    /// Connections served at once (0 for no limit)
+    #[arg(long, value_name = "COUNT")]
+    max_connections: Option<usize>,
+
+    /// What to do with clients over the connection limit: queue, reject or drop
+    #[arg(long, value_name = "POLICY")]
+    connection_limit_policy: Option<ConnectionLimitPolicy>,
+
+    /// Connections served at once for one client address (0 for no limit)
+    #[arg(long, value_name = "COUNT")]
+    max_connections_per_ip: Option<usize>,
+
+    /// Request budget of each connection, as RATE[:BURST] requests per second
+    #[arg(long, value_name = "RATE[:BURST]")]
+    rate_limit_per_connection: Option<RateLimit>,
+
+    /// Request budget shared by the connections of one client address, as RATE[:BURST]
+    #[arg(long, value_name = "RATE[:BURST]")]
+    rate_limit_per_ip: Option<RateLimit>,
+
+    /// Requests one connection may have in progress at once
+    #[arg(long, value_name = "COUNT")]
+    max_in_flight: Option<usize>,
+
+    /// Pushed messages queued per connection
+    #[arg(long, value_name = "COUNT")]
+    push_queue_size: Option<usize>,
+
+    /// What to do with a connection whose push queue is full: drop or disconnect
+    #[arg(long, value_name = "POLICY")]
+    slow_consumer_policy: Option<SlowConsumerPolicy>,
+
+    /// Topics with a retained publication kept at once
+    #[arg(long, value_name = "COUNT")]
+    max_retained_topics: Option<usize>,
+
+    /// Close connections whose first frame is not a Hello
+    #[arg(long)]
+    require_handshake: bool,
+
+    /// Close connections idle for this long (0 for no timeout)
+    #[arg(long, value_name = "MS")]
+    idle_timeout_ms: Option<u64>,
+
+    /// Close connections that take longer than this to send a frame (0 for no timeout)
+    #[arg(long, value_name = "MS")]
+    read_timeout_ms: Option<u64>,
+
+    /// Close connections whose response cannot be written in time (0 for no timeout)
+    #[arg(long, value_name = "MS")]
+    write_timeout_ms: Option<u64>,
+
+    /// Send a TIMED_OUT error frame before closing a connection on an idle or read timeout
+    #[arg(long)]
+    send_timeout_error: bool,
+
+    /// How long shutdown waits for open connections before aborting them
+    #[arg(long, value_name = "MS")]
+    drain_timeout_ms: Option<u64>,
+
+    /// How AddRequest overflows are answered: error, saturate, wrap or widen
+    #[arg(long, value_name = "POLICY")]
+    overflow_policy: Option<OverflowPolicy>,
+
+    /// Serve Prometheus metrics over HTTP on this address, e.g. 0.0.0.0:9100
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<String>,
+
+    /// Serve TLS with this PEM certificate chain; needs --tls-key
+    #[arg(long, value_name = "FILE")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate
+    #[arg(long, value_name = "FILE")]
+    tls_key: Option<PathBuf>,
+
+    /// Require client certificates issued by a CA in this PEM bundle
+    #[arg(long, value_name = "FILE")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Accept clients only from this CIDR block, e.g. 10.20.0.0/16; repeat for several
+    #[arg(long, value_name = "CIDR")]
+    ip_allow: Vec<IpNet>,
+
+    /// Refuse clients from this CIDR block; repeat for several
+    #[arg(long, value_name = "CIDR")]
+    ip_deny: Vec<IpNet>,
+
+    /// Require clients without a certificate to authenticate with a secret from this TOML token file
+    #[arg(long, value_name = "FILE")]
+    auth_token_file: Option<PathBuf>,
+
+    /// Log verbosity: off, error, warn, info, debug or trace
+    #[arg(short, long, value_name = "LEVEL")]
+    log_level: Option<log::LevelFilter>,
+
+    /// Log line format: text, or json for log pipelines
+    #[arg(long, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+}
+
+impl Args {
+    /// Resolves the final configuration from the file, the environment and the flags
+    fn load_config(&self) -> std::io::Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::from_toml_file(path)?,
+            None => ServerConfig::default(),
+        };
+        config.apply_env()?;
+
+        if let Some(bind) = &self.bind {
+            config.bind_addr = bind.clone();
+        }
+        if let Some(max_frame_size) = self.max_frame_size {
+            config.max_frame_size = max_frame_size;
+        }
+        if let Some(oversize_frame_policy) = self.oversize_frame_policy {
+            config.oversize_frame_policy = oversize_frame_policy;
+        }
+        if let Some(max_connections) = self.max_connections {
+            config.max_connections = (max_connections > 0).then_some(max_connections);
+        }
+        if let Some(policy) = self.connection_limit_policy {
+            config.connection_limit_policy = policy;
+        }
+        if let Some(max_connections_per_ip) = self.max_connections_per_ip {
+            config.max_connections_per_ip = (max_connections_per_ip > 0).then_some(max_connections_per_ip);
+        }
+        if let Some(limit) = self.rate_limit_per_connection {
+            config.rate_limit.per_connection = Some(limit);
+        }
+        if let Some(limit) = self.rate_limit_per_ip {
+            config.rate_limit.per_ip = Some(limit);
+        }
+        if let Some(addr) = &self.metrics_addr {
+            config.metrics_addr = Some(addr.clone());
+        }
+        if let Some(max_in_flight) = self.max_in_flight {
+            config.max_in_flight = max_in_flight;
+        }
+        if let Some(push_queue_size) = self.push_queue_size {
+            config.push_queue_size = push_queue_size;
+        }
+        if let Some(policy) = self.slow_consumer_policy {
+            config.slow_consumer_policy = policy;
+        }
+        if let Some(max_retained_topics) = self.max_retained_topics {
+            config.max_retained_topics = max_retained_topics;
+        }
+        if self.require_handshake {
+            config.require_handshake = true;
+        }
+        if let Some(ms) = self.idle_timeout_ms {
+            config.idle_timeout = millis(ms);
+        }
+        if let Some(ms) = self.read_timeout_ms {
+            config.read_timeout = millis(ms);
+        }
+        if let Some(ms) = self.write_timeout_ms {
+            config.write_timeout = millis(ms);
+        }
+        if self.send_timeout_error {
+            config.send_timeout_error = true;
+        }
+        if let Some(ms) = self.drain_timeout_ms {
+            config.drain_timeout = Duration::from_millis(ms);
+        }
+        if let Some(overflow_policy) = self.overflow_policy {
+            config.overflow_policy = overflow_policy;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log_format = log_format;
+        }
+        if self.tls_cert.is_some() || self.tls_key.is_some() || self.tls_client_ca.is_some() {
+            let tls = config.tls.get_or_insert_with(|| TlsConfig::new("", ""));
+            if let Some(cert) = &self.tls_cert {
+                tls.cert_path = cert.clone();
+            }
+            if let Some(key) = &self.tls_key {
+                tls.key_path = key.clone();
+            }
+            if let Some(ca) = &self.tls_client_ca {
+                tls.client_ca_path = Some(ca.clone());
+            }
+        }
+        if !self.ip_allow.is_empty() {
+            config.ip_filter.allow = self.ip_allow.clone();
+        }
+        if !self.ip_deny.is_empty() {
+            config.ip_filter.deny = self.ip_deny.clone();
+        }
+        if let Some(path) = &self.auth_token_file {
+            config.auth_token_file = Some(path.clone());
+        }
+        if let Some(tls) = &config.tls {
+            if tls.cert_path.as_os_str().is_empty() || tls.key_path.as_os_str().is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "TLS needs both a certificate and a key",
+                ));
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Installs the tracing subscriber; RUST_LOG, when set, refines the configured level
+fn init_logging(config: &ServerConfig) {
+    let level = match config.log_level {
+        log::LevelFilter::Off => LevelFilter::OFF,
+        log::LevelFilter::Error => LevelFilter::ERROR,
+        log::LevelFilter::Warn => LevelFilter::WARN,
+        log::LevelFilter::Info => LevelFilter::INFO,
+        log::LevelFilter::Debug => LevelFilter::DEBUG,
+        log::LevelFilter::Trace => LevelFilter::TRACE,
+    };
+    let filter = EnvFilter::builder().with_default_directive(level.into()).from_env_lossy();
+
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        // Each line carries the fields of the request and connection spans it was logged in
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
+
+/// Converts a millisecond flag, where 0 disables the timeout
+fn millis(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let config = match args.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            return ExitCode::from(EXIT_CONFIG_ERROR);
+        }
+    };
+
+    init_logging(&config);
+
+    let server = match Server::builder().config(config).build().await {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            error!("Failed to start server: {}", e);
+            return ExitCode::from(EXIT_BIND_ERROR);
+        }
+    };
+
+    let mut runner = tokio::spawn({
+        let server = server.clone();
+        async move { server.run().await }
+    });
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(args, server.clone()));
+
+    // Wait for SIGINT/SIGTERM, or for the server to stop on its own
+    let result = tokio::select! {
+        signal = shutdown_signal() => {
+            info!("Received {}, shutting down.", signal);
+            server.shutdown().await; // waits for open connections to drain
+            runner.await
+        }
+        result = &mut runner => result,
+    };
+
+    match result {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(e)) => {
+            error!("Server failed: {}", e);
+            ExitCode::from(EXIT_RUNTIME_ERROR)
+        }
+        Err(e) => {
+            error!("Server task panicked: {}", e);
+            ExitCode::from(EXIT_RUNTIME_ERROR)
+        }
+    }
+}
+
+/// Resolves with the name of the first termination signal received
+#[cfg(unix)]
+async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+/// Reloads the IP filter from the configuration sources on every SIGHUP; a configuration
+/// that fails to load is logged and the lists in force are kept
+#[cfg(unix)]
+async fn reload_on_hangup(args: Args, server: Arc<Server>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        match args.load_config() {
+            Ok(config) => server.set_ip_filter(config.ip_filter),
+            Err(e) => error!("Keeping the IP filter, the configuration failed to reload: {}", e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> &'static str {
+    tokio::signal::ctrl_c().await.expect("Failed to install Ctrl-C handler");
+    "Ctrl-C"
+}
@@ -0,0 +1,354 @@
+use crate::handler::MessageKind;
+use tracing::{debug, error, info};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+
+/// Upper bounds of the handler latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// Largest HTTP request head the metrics listener reads before giving up
+const MAX_HTTP_REQUEST: usize = 8192;
+
+/// Time a scraper has to send its request and read the answer
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters kept by a [`Server`](crate::server::Server) while it runs.
+///
+/// Shared by every connection and read with [`Server::metrics`](crate::server::Server::metrics).
+/// [`ServerMetrics::encode`] renders them in the Prometheus text format, which
+/// the server also serves over HTTP when `metrics_addr` is configured.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    accepted_connections: AtomicU64,
+    active_connections: AtomicU64,
+    oversize_frames: AtomicU64,
+    refused_connections: AtomicU64,
+    filtered_connections: AtomicU64,
+    rate_limited_requests: AtomicU64,
+    denied_requests: AtomicU64,
+    decode_failures: AtomicU64,
+    tls_handshake_failures: AtomicU64,
+    authentication_failures: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    handshakes: AtomicU64,
+    pushed_messages: AtomicU64,
+    dropped_pushes: AtomicU64,
+    requests: PerKind<AtomicU64>,
+    latency: PerKind<Histogram>,
+}
+
+/// One value per message kind; the map is filled once, so updates need no lock
+#[derive(Debug)]
+struct PerKind<T>(HashMap<MessageKind, T>);
+
+impl<T: Default> Default for PerKind<T> {
+    fn default() -> Self {
+        PerKind(MessageKind::ALL.iter().map(|&kind| (kind, T::default())).collect())
+    }
+}
+
+impl<T> PerKind<T> {
+    fn get(&self, kind: MessageKind) -> &T {
+        &self.0[&kind]
+    }
+}
+
+/// Cumulative histogram in the Prometheus layout: one counter per bucket bound, plus the sum and count
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl ServerMetrics {
+    /// Connections accepted and admitted since the server started
+    pub fn accepted_connections(&self) -> u64 {
+        self.accepted_connections.load(Ordering::Relaxed)
+    }
+
+    /// Connections currently being served
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Frames rejected because their declared length exceeded `max_frame_size`
+    pub fn oversize_frames(&self) -> u64 {
+        self.oversize_frames.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed on arrival because of a connection limit
+    pub fn refused_connections(&self) -> u64 {
+        self.refused_connections.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed on arrival because the IP filter does not allow their address
+    pub fn filtered_connections(&self) -> u64 {
+        self.filtered_connections.load(Ordering::Relaxed)
+    }
+
+    /// Requests answered with `RATE_LIMITED` instead of being handled
+    pub fn rate_limited_requests(&self) -> u64 {
+        self.rate_limited_requests.load(Ordering::Relaxed)
+    }
+
+    /// Requests answered with `PERMISSION_DENIED` because the ACL does not allow them
+    pub fn denied_requests(&self) -> u64 {
+        self.denied_requests.load(Ordering::Relaxed)
+    }
+
+    /// Frames that could not be decoded as a `ClientMessage`, or carried no message
+    pub fn decode_failures(&self) -> u64 {
+        self.decode_failures.load(Ordering::Relaxed)
+    }
+
+    /// Accepted connections whose TLS handshake failed or timed out
+    pub fn tls_handshake_failures(&self) -> u64 {
+        self.tls_handshake_failures.load(Ordering::Relaxed)
+    }
+
+    /// `Authenticate` requests whose credentials were refused
+    pub fn authentication_failures(&self) -> u64 {
+        self.authentication_failures.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read from client sockets
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of response frames written to client sockets
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Messages queued for clients by `Server::broadcast` and `Server::send_to`
+    pub fn pushed_messages(&self) -> u64 {
+        self.pushed_messages.load(Ordering::Relaxed)
+    }
+
+    /// Pushed messages not delivered because the connection's queue was full
+    pub fn dropped_pushes(&self) -> u64 {
+        self.dropped_pushes.load(Ordering::Relaxed)
+    }
+
+    /// Requests of `kind` decoded, whether or not they were handled
+    pub fn requests(&self, kind: MessageKind) -> u64 {
+        self.requests.get(kind).load(Ordering::Relaxed)
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        counter(&mut out, "server_connections_accepted_total", "Connections accepted and admitted.", self.accepted_connections());
+        gauge(&mut out, "server_connections_active", "Connections currently being served.", self.active_connections());
+        counter(&mut out, "server_connections_refused_total", "Connections refused because of a connection limit.", self.refused_connections());
+        counter(&mut out, "server_connections_filtered_total", "Connections refused by the IP allow and deny lists.", self.filtered_connections());
+        counter(&mut out, "server_handshakes_total", "Hello frames received.", self.handshakes.load(Ordering::Relaxed));
+        counter(&mut out, "server_decode_failures_total", "Frames that could not be decoded.", self.decode_failures());
+        counter(&mut out, "server_tls_handshake_failures_total", "TLS handshakes that failed or timed out.", self.tls_handshake_failures());
+        counter(&mut out, "server_authentication_failures_total", "Authenticate requests with refused credentials.", self.authentication_failures());
+        counter(&mut out, "server_oversize_frames_total", "Frames rejected for exceeding max_frame_size.", self.oversize_frames());
+        counter(&mut out, "server_rate_limited_requests_total", "Requests refused by a rate limit.", self.rate_limited_requests());
+        counter(&mut out, "server_denied_requests_total", "Requests refused by the access control list.", self.denied_requests());
+        counter(&mut out, "server_received_bytes_total", "Bytes read from clients.", self.bytes_received());
+        counter(&mut out, "server_sent_bytes_total", "Bytes written to clients.", self.bytes_sent());
+        counter(&mut out, "server_pushed_messages_total", "Messages pushed to clients.", self.pushed_messages());
+        counter(&mut out, "server_dropped_pushes_total", "Pushed messages dropped for a full queue.", self.dropped_pushes());
+
+        header(&mut out, "server_requests_total", "Requests decoded, by message type.", "counter");
+        for kind in MessageKind::ALL {
+            let _ = writeln!(out, "server_requests_total{{type=\"{}\"}} {}", kind, self.requests(kind));
+        }
+
+        let name = "server_handler_duration_seconds";
+        header(&mut out, name, "Time taken to handle a request, by message type.", "histogram");
+        for kind in MessageKind::ALL {
+            let histogram = self.latency.get(kind);
+            let mut cumulative = 0;
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(out, "{}_bucket{{type=\"{}\",le=\"{}\"}} {}", name, kind, bound, cumulative);
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+            let _ = writeln!(out, "{}_bucket{{type=\"{}\",le=\"+Inf\"}} {}", name, kind, count);
+            let _ = writeln!(out, "{}_sum{{type=\"{}\"}} {}", name, kind, sum);
+            let _ = writeln!(out, "{}_count{{type=\"{}\"}} {}", name, kind, count);
+        }
+        out
+    }
+
+    pub(crate) fn record_connection_opened(&self) {
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_oversize_frame(&self) {
+        self.oversize_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_refused_connection(&self) {
+        self.refused_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_filtered_connection(&self) {
+        self.filtered_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rate_limited_request(&self) {
+        self.rate_limited_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_denied_request(&self) {
+        self.denied_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_decode_failure(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_tls_handshake_failure(&self) {
+        self.tls_handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_authentication_failure(&self) {
+        self.authentication_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_bytes_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_pushed_message(&self) {
+        self.pushed_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped_push(&self) {
+        self.dropped_pushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a decoded request; `None` is a `Hello`
+    pub(crate) fn record_request(&self, kind: Option<MessageKind>) {
+        match kind {
+            Some(kind) => self.requests.get(kind).fetch_add(1, Ordering::Relaxed),
+            None => self.handshakes.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub(crate) fn record_latency(&self, kind: MessageKind, elapsed: Duration) {
+        self.latency.get(kind).observe(elapsed);
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Answers HTTP scrapes of `/metrics`; runs until the future is dropped
+pub(crate) async fn serve(listener: &TcpListener, metrics: Arc<ServerMetrics>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving metrics on http://{}/metrics", addr);
+    }
+    let mut scrapes = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    let metrics = metrics.clone();
+                    scrapes.spawn(async move {
+                        match tokio::time::timeout(HTTP_TIMEOUT, scrape(stream, &metrics)).await {
+                            Ok(Err(e)) => debug!("Metrics request from {} failed: {}", addr, e),
+                            Err(_) => debug!("Metrics request from {} timed out", addr),
+                            Ok(Ok(())) => {}
+                        }
+                    });
+                }
+                Err(e) => error!("Error accepting metrics connection: {}", e),
+            },
+            Some(_) = scrapes.join_next(), if !scrapes.is_empty() => {}
+        }
+    }
+}
+
+/// Reads one HTTP request and answers it, then closes the connection
+async fn scrape(mut stream: TcpStream, metrics: &ServerMetrics) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_HTTP_REQUEST {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+        let bytes_read = stream.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..bytes_read]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let path = target.split('?').next().unwrap_or_default();
+    match (method, path) {
+        ("GET", "/metrics") => respond(&mut stream, "200 OK", &metrics.encode()).await,
+        (_, "/metrics") => respond(&mut stream, "405 Method Not Allowed", "").await,
+        _ => respond(&mut stream, "404 Not Found", "").await,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
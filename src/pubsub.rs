@@ -0,0 +1,201 @@
+use crate::handler::{ConnectionContext, Handler, HandlerError};
+use crate::message::{client_message, server_message, ErrorCode, Publication, ServerMessage, TopicAck};
+use crate::registry::Registry;
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+/// Whether `topic` matches `filter`.
+///
+/// Both are split into '/'-separated levels. A `+` level of the filter matches
+/// exactly one level, and a trailing `#` matches any remaining levels, including
+/// none: `devices/#` matches `devices` and `devices/42/temperature`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        if level == "#" {
+            return true;
+        }
+        match topic_levels.next() {
+            Some(t) if level == "+" || level == t => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Checks a Subscribe or Unsubscribe filter: non-empty, with `+` and `#` only as
+/// whole levels and `#` only as the last one
+pub fn validate_filter(filter: &str) -> Result<(), HandlerError> {
+    if filter.is_empty() {
+        return Err(invalid_topic("Topic filter is empty"));
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        let wildcard = level.contains(['+', '#']);
+        if wildcard && *level != "+" && *level != "#" {
+            return Err(invalid_topic(format!("Wildcards must fill a whole level: {}", filter)));
+        }
+        if *level == "#" && i + 1 != levels.len() {
+            return Err(invalid_topic(format!("# must be the last level: {}", filter)));
+        }
+    }
+    Ok(())
+}
+
+/// Checks a Publish topic: non-empty and without wildcards
+pub fn validate_topic(topic: &str) -> Result<(), HandlerError> {
+    if topic.is_empty() {
+        return Err(invalid_topic("Topic is empty"));
+    }
+    if topic.contains(['+', '#']) {
+        return Err(invalid_topic(format!("Topics cannot contain wildcards: {}", topic)));
+    }
+    Ok(())
+}
+
+fn invalid_topic(message: impl Into<String>) -> HandlerError {
+    HandlerError::new(ErrorCode::InvalidTopic, message)
+}
+
+/// Routes publications to the subscribed connections and keeps the retained ones.
+///
+/// Registered by the server for `subscribe`, `unsubscribe` and `publish` unless
+/// other handlers were registered for them. Clones share their state.
+#[derive(Clone)]
+pub(crate) struct PubSubHandler {
+    registry: Arc<Registry>,
+    retained: Arc<Mutex<HashMap<String, Vec<u8>>>>, // Last retained payload per topic
+    max_retained_topics: usize,
+}
+
+impl PubSubHandler {
+    pub fn new(registry: Arc<Registry>, max_retained_topics: usize) -> Self {
+        PubSubHandler {
+            registry,
+            retained: Arc::new(Mutex::new(HashMap::new())),
+            max_retained_topics,
+        }
+    }
+
+    /// Subscribes the connection and pushes it the retained publications the filter matches.
+    ///
+    /// They are all the subscriber asked for, so instead of the slow consumer policy applying,
+    /// each waits for room in the push queue; the connection's writer drains it meanwhile.
+    async fn subscribe(&self, filter: String, ctx: &ConnectionContext) -> Result<u32, HandlerError> {
+        validate_filter(&filter)?;
+        let connection = self
+            .registry
+            .get(ctx.connection_id)
+            .ok_or_else(|| HandlerError::new(ErrorCode::UnknownError, "Connection is closing"))?;
+        if !connection.subscribe(&filter) {
+            return Ok(0); // retained publications were sent with the first Subscribe
+        }
+        info!("Connection {} subscribed to {}", ctx.connection_id, filter);
+
+        let retained: Vec<_> = self
+            .retained
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(topic, _)| topic_matches(&filter, topic))
+            .map(|(topic, payload)| (topic.clone(), payload.clone()))
+            .collect();
+        let mut delivered = 0;
+        for (topic, payload) in retained {
+            if !self.registry.push_waiting(&connection, publication(&topic, payload, true)).await {
+                break; // the connection is closing
+            }
+            delivered += 1;
+        }
+        Ok(delivered)
+    }
+
+    fn unsubscribe(&self, filter: String, ctx: &ConnectionContext) -> Result<(), HandlerError> {
+        validate_filter(&filter)?;
+        if let Some(connection) = self.registry.get(ctx.connection_id) {
+            if connection.unsubscribe(&filter) {
+                info!("Connection {} unsubscribed from {}", ctx.connection_id, filter);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes the payload to every subscribed connection once, returning how many it was queued for.
+    ///
+    /// Retaining on a new topic once `max_retained_topics` are kept is refused, and nothing is pushed.
+    fn publish(&self, topic: &str, payload: Vec<u8>, retain: bool) -> Result<u32, HandlerError> {
+        validate_topic(topic)?;
+        if retain {
+            let mut retained = self.retained.lock().unwrap();
+            if payload.is_empty() {
+                retained.remove(topic);
+            } else if retained.len() >= self.max_retained_topics && !retained.contains_key(topic) {
+                return Err(HandlerError::new(
+                    ErrorCode::TooManyRetainedTopics,
+                    format!("The server already retains {} topics", self.max_retained_topics),
+                ));
+            } else {
+                retained.insert(topic.to_string(), payload.clone());
+            }
+        }
+
+        let delivered = self
+            .registry
+            .all()
+            .iter()
+            .filter(|connection| connection.is_subscribed(topic))
+            .filter(|connection| self.registry.push(connection, publication(topic, payload.clone(), false)))
+            .count();
+        Ok(delivered as u32)
+    }
+}
+
+#[async_trait]
+impl Handler for PubSubHandler {
+    async fn handle(
+        &self,
+        message: client_message::Message,
+        ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        let (topic, delivered) = match message {
+            client_message::Message::Subscribe(subscribe) => {
+                let delivered = self.subscribe(subscribe.filter.clone(), ctx).await?;
+                (subscribe.filter, delivered)
+            }
+            client_message::Message::Unsubscribe(unsubscribe) => {
+                self.unsubscribe(unsubscribe.filter.clone(), ctx)?;
+                (unsubscribe.filter, 0)
+            }
+            client_message::Message::Publish(publish) => {
+                let delivered = self.publish(&publish.topic, publish.payload, publish.retain)?;
+                (publish.topic, delivered)
+            }
+            _ => {
+                return Err(HandlerError::new(
+                    ErrorCode::UnsupportedMessage,
+                    "Expected Subscribe, Unsubscribe or Publish",
+                ))
+            }
+        };
+
+        Ok(ServerMessage {
+            message: Some(server_message::Message::TopicAck(TopicAck { topic, delivered })),
+            ..Default::default()
+        })
+    }
+}
+
+fn publication(topic: &str, payload: Vec<u8>, retained: bool) -> ServerMessage {
+    ServerMessage {
+        message: Some(server_message::Message::Publication(Publication {
+            topic: topic.to_string(),
+            payload,
+            retained,
+        })),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::{RateLimit, RateLimitConfig};
+use crate::handler::MessageKind;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Per-IP buckets kept before full ones are first pruned; a full bucket is the same as a new one
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Token bucket: holds up to `burst` tokens, refilled at `rate` per second; each request takes one
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(f64::from(limit.burst));
+        self.updated = now;
+    }
+
+    /// Whether a request could take a token now, without taking it
+    fn has_token(&mut self, limit: RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        self.tokens >= 1.0
+    }
+
+    fn try_take(&mut self, limit: RateLimit, now: Instant) -> bool {
+        let available = self.has_token(limit, now);
+        if available {
+            self.tokens -= 1.0;
+        }
+        available
+    }
+}
+
+/// A message kind with its own limit has its own bucket; the others share the `None` bucket
+type BucketKey = Option<MessageKind>;
+
+/// The limit applying to `kind` and the bucket it is counted in
+fn per_connection_limit(config: &RateLimitConfig, kind: MessageKind) -> Option<(BucketKey, RateLimit)> {
+    match config.messages.get(&kind).and_then(|limits| limits.per_connection) {
+        Some(limit) => Some((Some(kind), limit)),
+        None => config.per_connection.map(|limit| (None, limit)),
+    }
+}
+
+fn per_ip_limit(config: &RateLimitConfig, kind: MessageKind) -> Option<(BucketKey, RateLimit)> {
+    match config.messages.get(&kind).and_then(|limits| limits.per_ip) {
+        Some(limit) => Some((Some(kind), limit)),
+        None => config.per_ip.map(|limit| (None, limit)),
+    }
+}
+
+/// Which budget a request exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Exceeded {
+    Connection,
+    Address,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exceeded::Connection => f.write_str("connection"),
+            Exceeded::Address => f.write_str("address"),
+        }
+    }
+}
+
+/// The per-IP buckets, shared by every connection of the server
+pub(crate) struct AddressLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<AddressBuckets>,
+}
+
+struct AddressBuckets {
+    buckets: HashMap<(IpAddr, BucketKey), TokenBucket>,
+    // Size at which full buckets are pruned next; twice what the last prune kept, so
+    // many busy addresses cost an occasional prune rather than one per request
+    prune_at: usize,
+}
+
+impl AddressLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        AddressLimiter {
+            config,
+            buckets: Mutex::new(AddressBuckets {
+                buckets: HashMap::new(),
+                prune_at: PRUNE_THRESHOLD,
+            }),
+        }
+    }
+
+    fn try_take(&self, ip: IpAddr, kind: MessageKind, now: Instant) -> bool {
+        let Some((key, limit)) = per_ip_limit(&self.config, kind) else {
+            return true;
+        };
+
+        let mut state = self.buckets.lock().unwrap();
+        let AddressBuckets { buckets, prune_at } = &mut *state;
+        if buckets.len() >= *prune_at {
+            let config = &self.config;
+            buckets.retain(|&(_, key), bucket| {
+                let limit = match key {
+                    Some(kind) => per_ip_limit(config, kind).map(|(_, limit)| limit),
+                    None => config.per_ip,
+                };
+                let Some(limit) = limit else { return false };
+                bucket.refill(limit, now);
+                bucket.tokens < f64::from(limit.burst)
+            });
+            *prune_at = (buckets.len() * 2).max(PRUNE_THRESHOLD);
+        }
+        buckets
+            .entry((ip, key))
+            .or_insert_with(|| TokenBucket::full(limit, now))
+            .try_take(limit, now)
+    }
+}
+
+/// Rate limits of one connection: its own buckets plus the shared ones of its address
+pub(crate) struct ConnectionLimiter {
+    ip: IpAddr,
+    buckets: HashMap<BucketKey, TokenBucket>,
+    addresses: Arc<AddressLimiter>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(ip: IpAddr, addresses: Arc<AddressLimiter>) -> Self {
+        ConnectionLimiter {
+            ip,
+            buckets: HashMap::new(),
+            addresses,
+        }
+    }
+
+    /// Takes a token for a request of `kind` from the connection's budget and from its address's;
+    /// a request either budget refuses takes nothing from the other
+    pub fn check(&mut self, kind: MessageKind) -> Result<(), Exceeded> {
+        let now = Instant::now();
+
+        let connection_bucket = match per_connection_limit(&self.addresses.config, kind) {
+            Some((key, limit)) => {
+                let bucket = self.buckets.entry(key).or_insert_with(|| TokenBucket::full(limit, now));
+                if !bucket.has_token(limit, now) {
+                    return Err(Exceeded::Connection);
+                }
+                Some(bucket)
+            }
+            None => None,
+        };
+        if !self.addresses.try_take(self.ip, kind, now) {
+            return Err(Exceeded::Address);
+        }
+        if let Some(bucket) = connection_bucket {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
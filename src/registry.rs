@@ -0,0 +1,296 @@
+use crate::config::SlowConsumerPolicy;
+use crate::handler::ConnectionContext;
+use crate::message::ServerMessage;
+use crate::metrics::ServerMetrics;
+use crate::pubsub::topic_matches;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::AbortHandle,
+};
+use tracing::warn;
+
+/// A snapshot of one live connection, as listed by [`Server::connections`](crate::server::Server::connections)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer_addr: SocketAddr,
+    pub connected_at: SystemTime,
+    /// Who the client is: the subject of its TLS certificate, or the identity an `Authenticate` proved
+    pub identity: Option<String>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Requests decoded on the connection, answered or not
+    pub requests: u64,
+    /// When the client last sent bytes or was sent a response
+    pub last_activity: SystemTime,
+}
+
+/// The live connections of a server, by connection id
+pub(crate) struct Registry {
+    connections: Mutex<HashMap<u64, Arc<Connection>>>,
+    slow_consumer_policy: SlowConsumerPolicy, // What a full push queue costs its connection
+    metrics: Arc<ServerMetrics>,
+}
+
+/// Counters of one connection, updated by its reading and writing halves
+pub(crate) struct Connection {
+    id: u64,
+    peer_addr: SocketAddr,
+    connected_at: SystemTime,
+    opened: Instant,
+    identity: OnceLock<String>, // Set by a verified client certificate or a successful Authenticate
+    max_frame_size: OnceLock<usize>, // Largest frame the client declared in its Hello
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    requests: AtomicU64,
+    last_activity: AtomicU64, // Microseconds after `opened`
+    task: Mutex<Task>,
+    pushes: mpsc::Sender<ServerMessage>, // Messages the server sends on its own, for the connection's writer
+    subscriptions: Mutex<Vec<String>>,   // Topic filters, each listed once
+}
+
+/// How far the connection's task is from being abortable
+enum Task {
+    Starting,
+    Running(AbortHandle),
+    Disconnected, // Asked to disconnect before its task was spawned
+}
+
+/// Keeps a connection listed; dropping it, also when its task is aborted, removes the entry
+pub(crate) struct Registration {
+    registry: Arc<Registry>,
+    connection: Arc<Connection>,
+    push_queue: mpsc::Receiver<ServerMessage>,
+}
+
+impl Registry {
+    pub fn new(slow_consumer_policy: SlowConsumerPolicy, metrics: Arc<ServerMetrics>) -> Self {
+        Registry {
+            connections: Mutex::new(HashMap::new()),
+            slow_consumer_policy,
+            metrics,
+        }
+    }
+
+    /// Lists a new connection, with room for `push_queue_size` pushed messages
+    pub fn register(self: &Arc<Self>, id: u64, peer_addr: SocketAddr, push_queue_size: usize) -> Registration {
+        let (pushes, push_queue) = mpsc::channel(push_queue_size.max(1));
+        let connection = Arc::new(Connection {
+            id,
+            peer_addr,
+            connected_at: SystemTime::now(),
+            opened: Instant::now(),
+            identity: OnceLock::new(),
+            max_frame_size: OnceLock::new(),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            last_activity: AtomicU64::new(0),
+            task: Mutex::new(Task::Starting),
+            pushes,
+            subscriptions: Mutex::new(Vec::new()),
+        });
+        self.connections.lock().unwrap().insert(id, connection.clone());
+        Registration {
+            registry: self.clone(),
+            connection,
+            push_queue,
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Connection>> {
+        self.connections.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Every live connection, in no particular order
+    pub fn all(&self) -> Vec<Arc<Connection>> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Every live connection, oldest first
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self.connections.lock().unwrap().values().map(|c| c.info()).collect();
+        connections.sort_by_key(|info| info.id);
+        connections
+    }
+
+    /// Aborts the task serving connection `id`, returning its peer; `None` if there is no such connection
+    pub fn disconnect(&self, id: u64) -> Option<SocketAddr> {
+        let connection = self.get(id)?;
+        connection.abort();
+        Some(connection.peer_addr)
+    }
+
+    /// Queues `message` for the connection's writer as a push, applying the
+    /// slow consumer policy when its queue is full; false if it was not queued
+    pub fn push(&self, connection: &Connection, mut message: ServerMessage) -> bool {
+        message.push = true;
+        message.request_id = 0;
+        match connection.pushes.try_send(message) {
+            Ok(()) => {
+                self.metrics.record_pushed_message();
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                self.metrics.record_dropped_push();
+                match self.slow_consumer_policy {
+                    SlowConsumerPolicy::Drop => {
+                        warn!("Dropping message pushed to {}: its queue is full", connection.peer_addr);
+                    }
+                    SlowConsumerPolicy::Disconnect => {
+                        warn!("Disconnecting slow consumer {} (connection {})", connection.peer_addr, connection.id);
+                        connection.abort();
+                    }
+                }
+                false
+            }
+            Err(TrySendError::Closed(_)) => false, // the connection is closing
+        }
+    }
+
+    /// Queues `message` for the connection's writer as a push, waiting for room in its
+    /// queue instead of applying the slow consumer policy; false if the connection is closing
+    pub async fn push_waiting(&self, connection: &Connection, mut message: ServerMessage) -> bool {
+        message.push = true;
+        message.request_id = 0;
+        if connection.pushes.send(message).await.is_err() {
+            return false;
+        }
+        self.metrics.record_pushed_message();
+        true
+    }
+
+    /// Pushes `message` to every connection, returning how many it was queued for
+    pub fn broadcast(&self, message: ServerMessage) -> usize {
+        self.all()
+            .iter()
+            .filter(|connection| self.push(connection, message.clone()))
+            .count()
+    }
+}
+
+impl Registration {
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+
+    /// The messages pushed to the connection, for its writer
+    pub fn push_queue(&mut self) -> &mut mpsc::Receiver<ServerMessage> {
+        &mut self.push_queue
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap().remove(&self.connection.id);
+    }
+}
+
+impl Connection {
+    /// What handlers are told about the connection
+    pub fn context(&self) -> ConnectionContext {
+        ConnectionContext {
+            connection_id: self.id,
+            peer_addr: self.peer_addr,
+            identity: self.identity.get().cloned(),
+        }
+    }
+
+    /// Records who the client authenticated as; the first identity sticks
+    pub fn set_identity(&self, identity: String) {
+        let _ = self.identity.set(identity);
+    }
+
+    /// Records the largest frame the client accepts, from its `Hello`
+    pub fn set_max_frame_size(&self, max_frame_size: usize) {
+        let _ = self.max_frame_size.set(max_frame_size);
+    }
+
+    /// Largest frame the client accepts, if it said so
+    pub fn max_frame_size(&self) -> Option<usize> {
+        self.max_frame_size.get().copied()
+    }
+
+    /// Makes the connection abortable once its task has been spawned
+    pub fn set_task(&self, handle: AbortHandle) {
+        let mut task = self.task.lock().unwrap();
+        match *task {
+            Task::Disconnected => handle.abort(),
+            _ => *task = Task::Running(handle),
+        }
+    }
+
+    /// Closes the connection by aborting its task, or as soon as it is spawned
+    pub fn abort(&self) {
+        let mut task = self.task.lock().unwrap();
+        if let Task::Running(handle) = &*task {
+            handle.abort();
+        }
+        *task = Task::Disconnected;
+    }
+
+    /// Adds a topic filter; false if the connection already had it
+    pub fn subscribe(&self, filter: &str) -> bool {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if subscriptions.iter().any(|s| s == filter) {
+            return false;
+        }
+        subscriptions.push(filter.to_string());
+        true
+    }
+
+    /// Removes a topic filter; false if the connection did not have it
+    pub fn unsubscribe(&self, filter: &str) -> bool {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let before = subscriptions.len();
+        subscriptions.retain(|s| s != filter);
+        subscriptions.len() != before
+    }
+
+    /// Whether any of the connection's filters matches `topic`
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscriptions.lock().unwrap().iter().any(|filter| topic_matches(filter, topic))
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn touch(&self) {
+        let micros = u64::try_from(self.opened.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.last_activity.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn info(&self) -> ConnectionInfo {
+        let active_after = Duration::from_micros(self.last_activity.load(Ordering::Relaxed));
+        ConnectionInfo {
+            id: self.id,
+            peer_addr: self.peer_addr,
+            connected_at: self.connected_at,
+            identity: self.identity.get().cloned(),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            last_activity: self.connected_at + active_after,
+        }
+    }
+}
@@ -1,100 +1,893 @@
-use crate::message::{client_message, server_message, ClientMessage, ServerMessage, AddResponse};
-use log::{error, info, warn};
+use crate::admission::{Admission, Refusal};
+use crate::auth::{self, Authenticator, StaticTokenAuthenticator};
+use crate::config::{
+    AclConfig, ConnectionLimitPolicy, IpFilterConfig, OversizeFramePolicy, RateLimitConfig, ServerConfig, SlowConsumerPolicy, TlsConfig,
+};
+use crate::framing::{encode_frame, FrameBuffer, FrameError};
+use crate::handler::{
+    ConnectionContext, Handler, HandlerError, HandlerRegistry, MessageKind, OverflowPolicy,
+};
+use crate::handshake;
+use crate::metrics::{self, ServerMetrics};
+use crate::rate_limit::{AddressLimiter, ConnectionLimiter};
+use crate::pubsub::PubSubHandler;
+use crate::registry::{Connection, Registration, Registry};
+use crate::tls::{self, Transport};
+pub use crate::registry::ConnectionInfo;
+use crate::message::{
+    authenticate, client_message, server_message, AuthChallenge, AuthResult, Authenticate, ClientMessage, ErrorCode,
+    Hello, ServerMessage,
+};
+use tracing::{error, info, info_span, warn, Instrument, Span};
 use prost::Message;
 use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
     },
-    
+    time::{Duration, Instant},
 };
 use tokio::{
-    net::{TcpListener, TcpStream}, // Asynchronous TCP networking
-    sync::Notify,                 // For signaling shutdowns
-    io::{AsyncReadExt, AsyncWriteExt}, // Asynchronous I/O
+    net::{TcpListener, TcpSocket, TcpStream},         // Asynchronous TCP networking
+    sync::{mpsc, watch, Notify, OwnedSemaphorePermit, Semaphore}, // For signaling shutdowns and capping connections and requests
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf}, // Asynchronous I/O
+    task::JoinSet,                                    // Tracks the connection tasks so shutdown can wait for them
 };
+use tokio_rustls::TlsAcceptor;
 
-const MAX_MESSAGE_SIZE: usize = 4096; // Define the maximum size for a message
+const READ_BUFFER_SIZE: usize = 4096; // Bytes requested from the socket per read
 
+/// What every connection of a running server uses to answer requests
+struct Services {
+    handlers: HandlerRegistry,
+    authenticator: Option<Arc<dyn Authenticator>>, // Set when clients must authenticate before other requests
+}
 
 struct Client {
-    stream: TcpStream,
+    stream: Box<dyn Transport>, // Plain TCP or TLS; the request loop does not care which
+    services: Arc<Services>,   // Shared with every other connection of the server
+    config: Arc<ServerConfig>, // Frame size limit and timeouts
+    metrics: Arc<ServerMetrics>,
+    rate_limits: ConnectionLimiter,
+    registration: Registration, // Lists the connection in the server's registry while it is served
+    shutdown: watch::Receiver<bool>, // Becomes true when the server starts draining connections
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Self {
-        Client { stream }
+    pub fn new(
+        stream: Box<dyn Transport>,
+        services: Arc<Services>,
+        config: Arc<ServerConfig>,
+        metrics: Arc<ServerMetrics>,
+        rate_limits: ConnectionLimiter,
+        registration: Registration,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        Client { stream, services, config, metrics, rate_limits, registration, shutdown }
     }
 
- pub async fn handle(&mut self) -> tokio::io::Result<()> {    // make it async function
-       
-        let mut buffer = vec![0u8; MAX_MESSAGE_SIZE]; //  buffer to handle messages
-        
-        loop {
-            let bytes_read = self.stream.read(&mut buffer).await?;
+    /// Serves the connection until the client leaves, an error occurs or the server drains.
+    ///
+    /// Requests are read and answered by two halves: the reader hands each request to a
+    /// handler and the writer sends the responses, in whatever order they complete,
+    /// along with the messages the server pushes to the connection. A `Hello` that does
+    /// not ask for pipelining keeps the responses in order, and one that declares a frame
+    /// limit keeps larger responses from being sent.
+    pub async fn handle(self) -> tokio::io::Result<()> {    // make it async function
+        let Client { stream, services, config, metrics, rate_limits, mut registration, shutdown } = self;
+        let connection = registration.connection().clone();
+        let (reader, writer) = tokio::io::split(stream);
+        let (responses, outbox) = mpsc::channel(config.max_in_flight.max(1));
+
+        let requests = Requests {
+            services,
+            config: config.clone(),
+            metrics: metrics.clone(),
+            rate_limits,
+            ctx: connection.context(),
+            connection: connection.clone(),
+            shutdown,
+            responses,
+            first_frame: true,
+            pipelining: true, // clients that skip the Hello keep the behaviour they had before it existed
+            challenge: None,
+        };
+        // A failed write ends the reader too; a finished reader closes the outbox, which ends the writer
+        tokio::try_join!(
+            requests.read(reader),
+            write_responses(writer, outbox, registration.push_queue(), config.write_timeout, connection, metrics)
+        )?;
+        Ok(())
+    }
+}
+
+/// The reading half of a connection
+struct Requests {
+    services: Arc<Services>,
+    config: Arc<ServerConfig>,
+    metrics: Arc<ServerMetrics>,
+    rate_limits: ConnectionLimiter,
+    ctx: ConnectionContext,
+    connection: Arc<Connection>,
+    shutdown: watch::Receiver<bool>,
+    responses: mpsc::Sender<ServerMessage>, // Queue of the writing half
+    first_frame: bool,                      // True until a request has been read; only it may be a Hello
+    pipelining: bool,                       // Whether requests with an id may be answered out of order
+    challenge: Option<(String, Vec<u8>)>,   // Key id and nonce of the last AuthChallenge, good for one answer
+}
+
+impl Requests {
+    async fn read(mut self, mut reader: ReadHalf<Box<dyn Transport>>) -> io::Result<()> {
+        let mut frames = FrameBuffer::new(self.config.max_frame_size); // reassembles frames split or coalesced by TCP
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE]; //  buffer to handle reads
+        let mut in_flight = JoinSet::new(); // Requests with an id that are still being handled
+        let slots = Arc::new(Semaphore::new(self.config.max_in_flight.max(1)));
+
+        let mut frame_started: Option<Instant> = None; // When the first bytes of the incomplete frame arrived
+
+        let result = loop {
+            // Between frames the idle timeout applies; a started frame must be complete within the
+            // read timeout, however the client spaces out its bytes
+            let limit = match frame_started {
+                None => self.config.idle_timeout,
+                Some(started) => self
+                    .config
+                    .read_timeout
+                    .map(|timeout| timeout.saturating_sub(started.elapsed())),
+            };
+            // Requests already read are still answered, so a shutdown can stop reading here
+            let read = tokio::select! {
+                result = with_deadline(limit, reader.read(&mut buffer)) => result,
+                _ = self.shutdown.wait_for(|stopping| *stopping) => {
+                    if !frames.is_empty() {
+                        warn!("Dropping {} bytes of an incomplete frame on shutdown.", frames.len());
+                    }
+                    info!("Server shutting down. Closing connection.");
+                    break Ok(());
+                }
+            };
+            let bytes_read = match read {
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    break self.timed_out(frame_started.is_some(), frames.len()).await;
+                }
+                Err(e) => break Err(e),
+            };
             if bytes_read == 0 {
+                if !frames.is_empty() {
+                    warn!("Client disconnected with {} bytes of an incomplete frame.", frames.len());
+                }
                 info!("Client disconnected.");
-                return Ok(());
+                break Ok(());
             }
 
-          
             info!("Bytes read: {}", bytes_read);
-            if bytes_read > MAX_MESSAGE_SIZE {
-                error!(
-                    "Message size {} exceeds the maximum allowed size {}. Rejecting.",
-                    bytes_read, MAX_MESSAGE_SIZE
-                );
-            } else {
-                info!("Processing message of size: {}", bytes_read);
+            self.metrics.record_bytes_received(bytes_read);
+            self.connection.record_received(bytes_read);
+            frames.extend(&buffer[..bytes_read]);
+            let buffered = frames.len();
+
+            // A single read may carry several frames, or only part of one
+            match self.process_frames(&mut frames, &mut in_flight, &slots).await {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
             }
 
+            // Bytes left over after a complete frame belong to a new one, whose deadline starts now
+            if frames.is_empty() {
+                frame_started = None;
+            } else if frame_started.is_none() || frames.len() < buffered {
+                frame_started = Some(Instant::now());
+            }
+        };
 
-    
-            // Decode ClientMessage
-            match ClientMessage::decode(&buffer[..bytes_read]) {
-                Ok(decoded_message) => match decoded_message.message {
-                    Some(client_message::Message::AddRequest(add_request)) => {
-                        info!(
-                            "Received AddRequest: a={}, b={}",
-                            add_request.a, add_request.b
-                        );
-    
-                        // Handle AddRequest
-                        let result = add_request.a + add_request.b;
-                        let response = ServerMessage {
-                            message: Some(server_message::Message::AddResponse(AddResponse { result })),
-                        };
-    
-                        // Encode and send response
-                        let payload = response.encode_to_vec();
-                        self.stream.write_all(&payload).await?;
-                    }
-                    Some(client_message::Message::EchoMessage(echo_message)) => {
-                        info!("Received EchoMessage: {}", echo_message.content);
-    
-                        // Echo back the message
-                        let response = ServerMessage {
-                            message: Some(server_message::Message::EchoMessage(echo_message)),
-                        };
-    
-                        let payload = response.encode_to_vec();
-                        self.stream.write_all(&payload).await?;
-                    }
-                    _ => {
-                        error!("Unsupported message type");
+        // Let the requests already accepted finish, so their responses are sent before closing
+        while let Some(joined) = in_flight.join_next().await {
+            if let Err(e) = joined {
+                error!("Request task failed: {}", e);
+            }
+        }
+        result
+    }
+
+    /// Handles every complete frame in `frames`; returns false once the connection must close
+    async fn process_frames(
+        &mut self,
+        frames: &mut FrameBuffer,
+        in_flight: &mut JoinSet<()>,
+        slots: &Arc<Semaphore>,
+    ) -> io::Result<bool> {
+        loop {
+            let frame = match frames.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(true),
+                Err(e @ FrameError::TooLarge { .. }) => {
+                    self.metrics.record_oversize_frame();
+                    self.respond(HandlerError::new(ErrorCode::FrameTooLarge, e.to_string()).into_response())
+                        .await?;
+                    if self.config.oversize_frame_policy == OversizeFramePolicy::Close {
+                        error!("Rejecting frame from {}: {}. Closing connection.", self.ctx.peer_addr, e);
+                        return Ok(false);
                     }
-                },
+                    warn!("Skipping frame from {}: {}", self.ctx.peer_addr, e);
+                    frames.discard_frame();
+                    continue;
+                }
+                Err(e @ FrameError::InvalidPrefix) => {
+                    error!("Rejecting frame: {}. Closing connection.", e);
+                    self.metrics.record_decode_failure();
+                    self.respond(HandlerError::new(ErrorCode::DecodeError, e.to_string()).into_response()).await?;
+                    return Ok(false); // the stream cannot be resynchronised after a bad prefix
+                }
+            };
+            info!("Processing message of size: {}", frame.len());
+
+            // Decode ClientMessage
+            let (request_id, message) = match ClientMessage::decode(frame.as_slice()) {
+                Ok(ClientMessage { message: Some(message), request_id }) => (request_id, message),
+                Ok(ClientMessage { message: None, request_id }) => {
+                    self.metrics.record_decode_failure();
+                    error!("Unsupported message type");
+                    let response = HandlerError::new(ErrorCode::UnsupportedMessage, "Unsupported message type");
+                    self.respond(with_request_id(response.into_response(), request_id)).await?;
+                    continue;
+                }
                 Err(e) => {
+                    self.metrics.record_decode_failure();
                     error!("Failed to decode message: {}", e);
+                    let response = HandlerError::new(ErrorCode::DecodeError, format!("Failed to decode message: {}", e));
+                    self.respond(response.into_response()).await?;
+                    continue;
+                }
+            };
+
+            let kind = MessageKind::of(&message);
+            let is_authenticate = matches!(message, client_message::Message::Authenticate(_));
+            if !is_authenticate {
+                self.metrics.record_request(kind);
+            }
+            self.connection.record_request();
+            // Child of the connection span; the duration is filled in once the handler returns
+            let span = info_span!(
+                "request",
+                r#type = if is_authenticate { "authenticate" } else { kind.map_or("hello", |kind| kind.name()) },
+                request_id,
+                duration_us = tracing::field::Empty,
+            );
+
+            if std::mem::take(&mut self.first_frame) {
+                if let client_message::Message::Hello(hello) = &message {
+                    let response = with_request_id(self.handshake(hello), request_id);
+                    let accepted = matches!(response.message, Some(server_message::Message::HelloAck(_)));
+                    self.respond(response).await?;
+                    if !accepted {
+                        return Ok(false); // the client cannot be served
+                    }
+                    continue;
+                }
+                if self.config.require_handshake {
+                    error!("Client {} did not open with a Hello. Closing connection.", self.ctx.peer_addr);
+                    let response = HandlerError::new(ErrorCode::HandshakeRequired, "The first frame must be a Hello");
+                    self.respond(with_request_id(response.into_response(), request_id)).await?;
+                    return Ok(false);
+                }
+            }
+
+            // Authenticating changes who the connection is, so it is answered here rather than by a handler
+            if let client_message::Message::Authenticate(authenticate) = message {
+                match self.authenticate(authenticate).await {
+                    Ok(response) => self.respond(with_request_id(response, request_id)).await?,
+                    Err(refusal) => {
+                        self.respond(with_request_id(refusal.into_response(), request_id)).await?;
+                        return Ok(false); // every guess costs a new connection
+                    }
+                }
+                continue;
+            }
+            if !self.is_authenticated() {
+                span.in_scope(|| warn!("Refusing request from {}: not authenticated", self.ctx.peer_addr));
+                let response = HandlerError::new(ErrorCode::Unauthenticated, "Send an Authenticate first");
+                self.respond(with_request_id(response.into_response(), request_id)).await?;
+                continue;
+            }
+
+            // Requests the ACL does not allow this identity are answered without reaching a handler
+            if let Some(kind) = kind {
+                let identity = self.ctx.identity.as_deref();
+                if !self.config.acl.allows(kind, identity) {
+                    self.metrics.record_denied_request();
+                    span.in_scope(|| {
+                        warn!(
+                            "Denying {} to {} ({}): not allowed by the ACL",
+                            kind,
+                            identity.unwrap_or("anonymous"),
+                            self.ctx.peer_addr
+                        )
+                    });
+                    let response = HandlerError::new(
+                        ErrorCode::PermissionDenied,
+                        format!("{} is not allowed for this connection", kind),
+                    );
+                    self.respond(with_request_id(response.into_response(), request_id)).await?;
+                    continue;
+                }
+            }
+
+            // Over-budget requests are answered without reaching a handler
+            if let Some(kind) = kind {
+                if let Err(exceeded) = self.rate_limits.check(kind) {
+                    self.metrics.record_rate_limited_request();
+                    span.in_scope(|| {
+                        warn!("Rate limiting {} from {}: {} budget exhausted", kind, self.ctx.peer_addr, exceeded)
+                    });
+                    let response = HandlerError::new(
+                        ErrorCode::RateLimited,
+                        format!("Too many {} requests for this {}; retry later", kind, exceeded),
+                    );
+                    self.respond(with_request_id(response.into_response(), request_id)).await?;
+                    continue;
+                }
+            }
+
+            if request_id == 0 || !self.pipelining {
+                // Without an id, or without pipelining agreed, the client matches responses by order,
+                // so answer it before reading on
+                let response = handle_request(&self.services.handlers, &self.metrics, message, &self.ctx, span).await;
+                self.respond(with_request_id(response, request_id)).await?;
+                continue;
+            }
+
+            // Waiting for a slot stops reading, which pushes back on a client that sends too fast
+            let permit = slots.clone().acquire_owned().await.expect("request semaphore is never closed");
+            let services = self.services.clone();
+            let metrics = self.metrics.clone();
+            let ctx = self.ctx.clone();
+            let responses = self.responses.clone();
+            in_flight.spawn(async move {
+                let response = handle_request(&services.handlers, &metrics, message, &ctx, span).await;
+                // Fails only when the writer has stopped, and then the connection is closing anyway
+                let _ = responses.send(with_request_id(response, request_id)).await;
+                drop(permit);
+            });
+        }
+    }
+
+    /// Logs an expired idle or read timeout and, if configured, tells the client before the connection closes
+    async fn timed_out(&self, mid_frame: bool, buffered: usize) -> io::Result<()> {
+        let reason = if mid_frame {
+            let limit = self.config.read_timeout.unwrap_or_default();
+            format!("Frame not complete within {} ms ({} bytes received)", limit.as_millis(), buffered)
+        } else {
+            let limit = self.config.idle_timeout.unwrap_or_default();
+            format!("No request within {} ms", limit.as_millis())
+        };
+        warn!("Closing connection from {}: {}", self.ctx.peer_addr, reason);
+
+        if self.config.send_timeout_error {
+            self.respond(HandlerError::new(ErrorCode::TimedOut, reason).into_response()).await?;
+        }
+        Ok(())
+    }
+
+    /// Answers a `Hello` with the agreed version and features, or with `INCOMPATIBLE_VERSION`,
+    /// and keeps what was agreed for the rest of the connection
+    fn handshake(&mut self, hello: &Hello) -> ServerMessage {
+        let supported_messages = self.services.handlers.kinds().iter().map(|kind| kind.name().to_string()).collect();
+        match handshake::negotiate(hello, supported_messages, self.config.max_frame_size) {
+            Ok(ack) => {
+                info!(
+                    "Client {} speaks protocol version {}, features {:?}",
+                    self.ctx.peer_addr, ack.protocol_version, ack.features
+                );
+                self.pipelining = ack.features.iter().any(|feature| feature == handshake::FEATURE_PIPELINING);
+                if hello.max_frame_size > 0 {
+                    self.connection.set_max_frame_size(usize::try_from(hello.max_frame_size).unwrap_or(usize::MAX));
+                }
+                ServerMessage {
+                    message: Some(server_message::Message::HelloAck(ack)),
+                    ..Default::default()
                 }
             }
-    
-            self.stream.flush().await?;
+            Err(reason) => {
+                error!("Rejecting client {}: {}", self.ctx.peer_addr, reason);
+                HandlerError::new(ErrorCode::IncompatibleVersion, reason).into_response()
+            }
+        }
+    }
+
+    /// Whether the connection may make requests: the server needs no authentication, or
+    /// the client proved who it is with its certificate or an `Authenticate`
+    fn is_authenticated(&self) -> bool {
+        self.services.authenticator.is_none() || self.ctx.identity.is_some()
+    }
+
+    /// Answers an `Authenticate` with a challenge to sign or with the identity the credentials prove.
+    ///
+    /// Refused credentials are returned as the error, after which the connection closes.
+    async fn authenticate(&mut self, authenticate: Authenticate) -> Result<ServerMessage, HandlerError> {
+        let Some(authenticator) = self.services.authenticator.clone() else {
+            return Ok(HandlerError::new(ErrorCode::UnsupportedMessage, "This server does not authenticate clients")
+                .into_response());
+        };
+        if let Some(identity) = &self.ctx.identity {
+            return Ok(HandlerError::new(
+                ErrorCode::UnsupportedMessage,
+                format!("Already authenticated as {}", identity),
+            )
+            .into_response());
+        }
+
+        let challenge = self.challenge.take();
+        let credentials = match &authenticate.credentials {
+            Some(authenticate::Credentials::Token(token)) => auth::Credentials::Token(token),
+            Some(authenticate::Credentials::ChallengeFor(key_id)) => {
+                // Issued whether or not the key id exists, so the answer does not tell
+                let nonce = auth::challenge()?;
+                self.challenge = Some((key_id.clone(), nonce.clone()));
+                return Ok(ServerMessage {
+                    message: Some(server_message::Message::AuthChallenge(AuthChallenge { nonce })),
+                    ..Default::default()
+                });
+            }
+            Some(authenticate::Credentials::Hmac(hmac)) => match &challenge {
+                Some((key_id, nonce)) if *key_id == hmac.key_id => auth::Credentials::Hmac {
+                    key_id,
+                    challenge: nonce,
+                    signature: &hmac.signature,
+                },
+                _ => return Err(self.refused(HandlerError::new(ErrorCode::Unauthenticated, "No challenge issued for this key id"))),
+            },
+            None => return Err(self.refused(HandlerError::new(ErrorCode::Unauthenticated, "No credentials"))),
+        };
+
+        let identity = authenticator.authenticate(credentials).await.map_err(|e| self.refused(e))?;
+        Span::current().record("identity", identity.as_str());
+        info!("Client {} authenticated as {}", self.ctx.peer_addr, identity);
+        self.connection.set_identity(identity.clone());
+        self.ctx.identity = Some(identity.clone());
+        Ok(ServerMessage {
+            message: Some(server_message::Message::AuthResult(AuthResult { identity })),
+            ..Default::default()
+        })
+    }
+
+    /// Counts and logs credentials that were refused
+    fn refused(&self, e: HandlerError) -> HandlerError {
+        self.metrics.record_authentication_failure();
+        warn!("Refusing credentials of {}: {}. Closing connection.", self.ctx.peer_addr, e.message);
+        e
+    }
+
+    /// Queues a response for the writer
+    async fn respond(&self, response: ServerMessage) -> io::Result<()> {
+        self.responses
+            .send(response)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Connection writer stopped"))
+    }
+}
+
+/// Dispatches a request to its handler inside the request's span, timing the handler
+async fn handle_request(
+    handlers: &HandlerRegistry,
+    metrics: &ServerMetrics,
+    message: client_message::Message,
+    ctx: &ConnectionContext,
+    span: Span,
+) -> ServerMessage {
+    let kind = MessageKind::of(&message);
+    let started = Instant::now();
+    let response = handlers.dispatch(message, ctx).instrument(span.clone()).await;
+    let elapsed = started.elapsed();
+    if let Some(kind) = kind {
+        metrics.record_latency(kind, elapsed);
+    }
+    span.record("duration_us", u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX));
+    span.in_scope(|| info!("Request handled"));
+    response
+}
+
+/// Copies the id of the request into its response
+fn with_request_id(mut response: ServerMessage, request_id: u64) -> ServerMessage {
+    response.request_id = request_id;
+    if let Some(server_message::Message::ErrorResponse(error)) = &mut response.message {
+        error.request_id = request_id;
+    }
+    response
+}
+
+/// The writing half of a connection: sends each queued response and pushed message, giving up after the write timeout
+async fn write_responses(
+    mut writer: WriteHalf<Box<dyn Transport>>,
+    mut outbox: mpsc::Receiver<ServerMessage>,
+    pushes: &mut mpsc::Receiver<ServerMessage>,
+    write_timeout: Option<Duration>,
+    connection: Arc<Connection>,
+    metrics: Arc<ServerMetrics>,
+) -> io::Result<()> {
+    loop {
+        // Responses go first; the connection ends once the reader is done and its responses are sent
+        let message = tokio::select! {
+            biased;
+            response = outbox.recv() => match response {
+                Some(response) => response,
+                None => break,
+            },
+            Some(push) = pushes.recv() => push,
+        };
+        let Some(message) = fit_frame(message, &connection) else {
+            continue;
+        };
+        let payload = encode_frame(&message);
+        let payload_len = payload.len();
+        let writer = &mut writer;
+        let written = with_deadline(write_timeout, async move {
+            writer.write_all(&payload).await?;
+            writer.flush().await
+        })
+        .await;
+
+        // The client is not reading, so an error frame could not be delivered either
+        if let Err(e) = written {
+            if e.kind() == io::ErrorKind::TimedOut {
+                warn!("Closing connection to {}: response not written in time", connection.context().peer_addr);
+            }
+            return Err(e);
+        }
+        metrics.record_bytes_sent(payload_len);
+        connection.record_sent(payload_len);
+    }
+    // Over TLS this sends close_notify, so the client can tell the end of the stream from a truncation;
+    // a client that is already gone does not make the connection fail
+    let _ = with_deadline(write_timeout, writer.shutdown()).await;
+    Ok(())
+}
+
+/// Checks a message against the largest frame the client declared in its `Hello`: a response
+/// that does not fit is replaced by a `FRAME_TOO_LARGE` error, a push that does not fit is dropped
+fn fit_frame(message: ServerMessage, connection: &Connection) -> Option<ServerMessage> {
+    let Some(max) = connection.max_frame_size() else {
+        return Some(message);
+    };
+    let len = message.encoded_len();
+    if len <= max {
+        return Some(message);
+    }
+    let peer_addr = connection.context().peer_addr;
+    if message.push {
+        warn!("Dropping push to {}: {} bytes exceed the client's limit of {}", peer_addr, len, max);
+        return None;
+    }
+    warn!("Refusing response to {}: {} bytes exceed the client's limit of {}", peer_addr, len, max);
+    let error = HandlerError::new(
+        ErrorCode::FrameTooLarge,
+        format!("Response of {} bytes exceeds the client's maximum frame size {}", len, max),
+    );
+    Some(with_request_id(error.into_response(), message.request_id))
+}
+
+/// Performs the TLS handshake of an accepted connection when the server serves TLS, counting failures;
+/// returns the stream and the identity of the client's certificate
+async fn secure(
+    stream: TcpStream,
+    tls: Option<&TlsAcceptor>,
+    config: &ServerConfig,
+    metrics: &ServerMetrics,
+) -> io::Result<(Box<dyn Transport>, Option<String>)> {
+    let timeout = config.tls.as_ref().map_or(Duration::ZERO, |tls| tls.handshake_timeout);
+    let result = tls::accept(tls, stream, timeout).await;
+    if result.is_err() {
+        metrics.record_tls_handshake_failure();
+    }
+    result
+}
+
+/// Awaits `operation`, failing with `TimedOut` if it takes longer than `limit`
+async fn with_deadline<T>(
+    limit: Option<Duration>,
+    operation: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, operation).await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No progress within {} ms", limit.as_millis()),
+            )
+        })?,
+        None => operation.await,
+    }
+}
+
+
+
+
+
+/// Configures and binds a [`Server`].
+///
+/// Starts from [`ServerConfig::default`] and the built-in handlers; each call
+/// overrides one setting, and a later call wins over an earlier one.
+pub struct ServerBuilder {
+    config: ServerConfig,
+    handlers: HandlerRegistry,
+    authenticator: Option<Arc<dyn Authenticator>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        ServerBuilder {
+            config: ServerConfig::default(),
+            handlers: HandlerRegistry::with_defaults(),
+            authenticator: None,
+        }
+    }
+
+    /// Replaces every setting with `config`, e.g. one loaded from a TOML file; handlers
+    /// registered before or after are kept
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Address to listen on; port `0` picks a free port
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.config.bind_addr = addr.into();
+        self
+    }
+
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.config.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Sets whether a frame above `max_frame_size` closes the connection or is skipped
+    pub fn oversize_frame_policy(mut self, policy: OversizeFramePolicy) -> Self {
+        self.config.oversize_frame_policy = policy;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.config.max_connections = max_connections;
+        self
+    }
+
+    /// Sets whether clients over `max_connections` are queued, rejected with an error or dropped
+    pub fn connection_limit_policy(mut self, policy: ConnectionLimitPolicy) -> Self {
+        self.config.connection_limit_policy = policy;
+        self
+    }
+
+    /// Connections served at once for one client IP address
+    pub fn max_connections_per_ip(mut self, max_connections_per_ip: Option<usize>) -> Self {
+        self.config.max_connections_per_ip = max_connections_per_ip;
+        self
+    }
+
+    /// Whether clients must open with a `Hello`; otherwise the handshake is optional
+    pub fn require_handshake(mut self, require_handshake: bool) -> Self {
+        self.config.require_handshake = require_handshake;
+        self
+    }
+
+    /// Serves the metrics over HTTP at `http://<addr>/metrics`; port `0` picks a free port
+    pub fn metrics_addr(mut self, addr: impl Into<String>) -> Self {
+        self.config.metrics_addr = Some(addr.into());
+        self
+    }
+
+    /// Serves every connection over TLS with the certificate and key in `tls`
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    /// Requires clients without a client certificate to authenticate with a secret from this token file
+    pub fn auth_token_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.auth_token_file = Some(path.into());
+        self
+    }
+
+    /// Requires clients without a client certificate to authenticate, checking their credentials with
+    /// `authenticator`; it takes precedence over `auth_token_file`
+    pub fn authenticator<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Accepts clients only from the `allow` blocks, and never from the `deny` blocks
+    pub fn ip_filter(mut self, ip_filter: IpFilterConfig) -> Self {
+        self.config.ip_filter = ip_filter;
+        self
+    }
+
+    /// Restricts which connection identities may send which message kinds
+    pub fn acl(mut self, acl: AclConfig) -> Self {
+        self.config.acl = acl;
+        self
+    }
+
+    /// Sets the request budgets per connection, per client address and per message kind
+    pub fn rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.config.rate_limit = rate_limit;
+        self
+    }
+
+    /// Messages pushed to one connection that may wait to be written
+    pub fn push_queue_size(mut self, push_queue_size: usize) -> Self {
+        self.config.push_queue_size = push_queue_size;
+        self
+    }
+
+    /// Sets what happens to a connection whose push queue is full
+    pub fn slow_consumer_policy(mut self, policy: SlowConsumerPolicy) -> Self {
+        self.config.slow_consumer_policy = policy;
+        self
+    }
+
+    /// Topics with a retained publication the server keeps at once
+    pub fn max_retained_topics(mut self, max_retained_topics: usize) -> Self {
+        self.config.max_retained_topics = max_retained_topics;
+        self
+    }
+
+    /// Requests with an id that one connection may have in progress at once
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.config.max_in_flight = max_in_flight;
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    /// Whether an expired idle or read timeout is reported to the client with a `TIMED_OUT` error frame
+    pub fn send_timeout_error(mut self, send_timeout_error: bool) -> Self {
+        self.config.send_timeout_error = send_timeout_error;
+        self
+    }
+
+    /// How long shutdown waits for open connections before aborting them
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.config.drain_timeout = timeout;
+        self
+    }
+
+    /// Sets how `AddRequest`s whose sum overflows an `i32` are answered by the built-in `AddHandler`.
+    ///
+    /// An `AddRequest` handler registered with [`handler`](Self::handler) or [`handlers`](Self::handlers)
+    /// wins, whichever call comes first.
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.config.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Registers `handler` for `kind`, replacing the built-in handler if there is one
+    pub fn handler<H: Handler + 'static>(mut self, kind: MessageKind, handler: H) -> Self {
+        self.handlers.register(kind, handler);
+        self
+    }
+
+    /// Replaces the whole handler registry; if it keeps the built-in `AddHandler`,
+    /// that handler follows the configured overflow policy
+    pub fn handlers(mut self, handlers: HandlerRegistry) -> Self {
+        self.handlers = handlers;
+        self
+    }
+
+    /// Binds the listener and creates the server
+    pub async fn build(mut self) -> tokio::io::Result<Server> {
+        self.config.validate()?;
+        let listener = bind_listener(&self.config).await?; // Asynchronously binds the server to the configured address.
+
+        info!("Server running on {}", listener.local_addr()?); // Log the actual port
+
+        let is_running = Arc::new(AtomicBool::new(true)); // Initially set to true to indicate the server is active.
+
+        let shutdown_notify = Arc::new(Notify::new()); // Used for signaling shutdown events to the server and its tasks
+
+        let metrics = Arc::new(ServerMetrics::default());
+        let admission = Arc::new(Admission::new(&self.config, metrics.clone()));
+        let address_limits = Arc::new(AddressLimiter::new(self.config.rate_limit.clone()));
+        let registry = Arc::new(Registry::new(self.config.slow_consumer_policy, metrics.clone()));
+
+        // Resolved here so the order of `config`, `overflow_policy` and `handlers` does not matter
+        self.handlers.apply_overflow_policy(self.config.overflow_policy);
+
+        // Topics are routed between the registered connections, unless other handlers serve them
+        let pubsub = PubSubHandler::new(registry.clone(), self.config.max_retained_topics);
+        for kind in [MessageKind::Subscribe, MessageKind::Unsubscribe, MessageKind::Publish] {
+            if self.handlers.get(kind).is_none() {
+                self.handlers.register(kind, pubsub.clone());
+            }
         }
- }
 
+        // Certificate problems surface here rather than on the first connection
+        let tls = self.config.tls.as_ref().map(tls::acceptor).transpose()?;
+        let authenticator = match (self.authenticator, &self.config.auth_token_file) {
+            (Some(authenticator), _) => Some(authenticator),
+            (None, Some(path)) => Some(Arc::new(StaticTokenAuthenticator::from_file(path)?) as Arc<dyn Authenticator>),
+            (None, None) => None,
+        };
+
+        // Scrapes are answered while `run` executes
+        let metrics_listener = match &self.config.metrics_addr {
+            Some(addr) => Some(TcpListener::bind(addr.as_str()).await?),
+            None => None,
+        };
+
+        let (drain_signal, _) = watch::channel(false); // Tells connection tasks to finish up
+        let (serving, _) = watch::channel(false); // True while `run` is accepting or draining
+
+        Ok(Server {
+            ip_filter: RwLock::new(self.config.ip_filter.clone()),
+            listener,
+            is_running,
+            shutdown_notify,
+            drain_signal,
+            serving,
+            config: Arc::new(self.config),
+            handlers: self.handlers,
+            admission,
+            address_limits,
+            metrics,
+            metrics_listener,
+            tls,
+            authenticator,
+            registry,
+            next_connection_id: AtomicU64::new(1),
+        })
+    }
 }
 
+/// Binds a listening socket with the configured socket options, trying each resolved address in turn
+async fn bind_listener(config: &ServerConfig) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(&config.bind_addr).await? {
+        match listen_on(addr, config) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} did not resolve to any address", config.bind_addr),
+        )
+    }))
+}
+
+fn listen_on(addr: SocketAddr, config: &ServerConfig) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(config.listener.reuse_address)?;
+    socket.bind(addr)?;
+    socket.listen(config.listener.backlog)
+}
 
 
 
@@ -105,68 +898,235 @@ pub struct Server {
     is_running: Arc<AtomicBool>,   // A shared, thread-safe boolean flag to track the server's running state.
 
     shutdown_notify: Arc<Notify>,  // A Tokio synchronization primitive that allows signaling multiple tasks to shut down.
+
+    drain_signal: watch::Sender<bool>, // Set to true once the accept loop stops, so connections close.
+
+    serving: watch::Sender<bool>, // True while `run` executes; `shutdown` waits for it to turn false.
+
+    config: Arc<ServerConfig>, // Limits and timeouts, shared by all connections.
+
+    handlers: HandlerRegistry, // Business logic for each message type, shared by all connections.
+
+    admission: Arc<Admission>, // Connection limits, and the count of connections being served.
+
+    ip_filter: RwLock<IpFilterConfig>, // Client addresses allowed to connect; replaced by `set_ip_filter`.
+
+    address_limits: Arc<AddressLimiter>, // Request budgets shared by the connections of each client address.
+
+    next_connection_id: AtomicU64, // Source of the ids handed to handlers in the ConnectionContext.
+
+    metrics: Arc<ServerMetrics>, // Counters updated by every connection.
+
+    metrics_listener: Option<TcpListener>, // HTTP listener serving the metrics to Prometheus, if configured.
+
+    tls: Option<TlsAcceptor>, // Wraps accepted connections in TLS, if configured.
+
+    authenticator: Option<Arc<dyn Authenticator>>, // Checks the credentials of clients without a certificate, if configured.
+
+    registry: Arc<Registry>, // The live connections, for listing and forced disconnects.
 }
 
 impl Server {
 
 
-   // add getter method to get the server address 
+   // add getter method to get the server address
    pub fn local_addr(&self) -> tokio::io::Result<std::net::SocketAddr> {
     self.listener.local_addr()
-   } 
+   }
 
-    /// Creates a new server instance
+    /// Creates a new server instance with the default configuration
     pub async fn new(addr: &str) -> tokio::io::Result<Self> {     // Creates a new Server instance, binds it to an address, and initializes its fields.
+        ServerBuilder::new().bind(addr).build().await
+    }
 
-        let listener = TcpListener::bind(addr).await?;    // Asynchronously binds the server to the given address.
-        
-        info!("Server running on {}", listener.local_addr()?); // Log the actual port
- 
-        let is_running = Arc::new(AtomicBool::new(true));    // Initially set to true to indicate the server is active.
+    /// Starts configuring a server
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// The configuration the server was built with
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
 
-        
-        let shutdown_notify = Arc::new(Notify::new());   // Used for signaling shutdown events to the server and its tasks
-         
+    /// The client address allow and deny lists in force
+    pub fn ip_filter(&self) -> IpFilterConfig {
+        self.ip_filter.read().unwrap().clone()
+    }
 
+    /// Replaces the client address allow and deny lists; connections already open are not affected
+    pub fn set_ip_filter(&self, ip_filter: IpFilterConfig) {
+        let list = |nets: &[_]| nets.iter().map(ToString::to_string).collect::<Vec<String>>().join(", ");
+        info!("IP filter now allows [{}] and denies [{}]", list(&ip_filter.allow), list(&ip_filter.deny));
+        *self.ip_filter.write().unwrap() = ip_filter;
+    }
 
-        Ok(Server {
-            listener,
-            is_running,
-            shutdown_notify,
-        })
+    /// Connections currently being served
+    pub fn active_connections(&self) -> usize {
+        self.metrics.active_connections() as usize
+    }
+
+    /// Counters of what the server has seen so far
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.metrics
     }
 
-    /// Runs the server, listening for incoming connections and handling them
+    /// The connections being served, oldest first
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.registry.list()
+    }
+
+    /// Closes connection `id` at once, dropping the requests it has in progress.
+    ///
+    /// Returns false if no connection with that id is open.
+    pub fn disconnect(&self, id: u64) -> bool {
+        match self.registry.disconnect(id) {
+            Some(peer_addr) => {
+                warn!("Disconnecting connection {} from {}", id, peer_addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pushes `message` to connection `id`, outside of any request.
+    ///
+    /// Returns false if there is no such connection or its push queue is full,
+    /// in which case the slow consumer policy applies.
+    pub fn send_to(&self, id: u64, message: ServerMessage) -> bool {
+        match self.registry.get(id) {
+            Some(connection) => self.registry.push(&connection, message),
+            None => false,
+        }
+    }
+
+    /// Pushes `message` to every connection, returning how many it was queued for
+    pub fn broadcast(&self, message: ServerMessage) -> usize {
+        self.registry.broadcast(message)
+    }
+
+    /// Address of the HTTP listener serving the metrics, if one is configured
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Runs the server, listening for incoming connections and handling them.
+    ///
+    /// After `stop` it closes the open connections, waiting up to the drain
+    /// timeout for them to finish their current request, and then returns.
     pub async fn run(&self) -> tokio::io::Result<()> {
         self.is_running.store(true, Ordering::SeqCst); // Set the server as running
+        self.serving.send_replace(true);
+        self.drain_signal.send_replace(false);
         info!("Server is running on {}", self.listener.local_addr()?);
 
-       
+        // The metrics stay available until the connections have drained
+        match &self.metrics_listener {
+            Some(listener) => {
+                tokio::select! {
+                    _ = self.serve_clients() => {}
+                    _ = metrics::serve(listener, self.metrics.clone()) => {}
+                }
+            }
+            None => self.serve_clients().await,
+        }
+
+        self.serving.send_replace(false);
+        info!("Server stopped.");
+        Ok(())
+    }
+
+    /// Accepts and serves clients until the server is stopped, then drains the connections
+    async fn serve_clients(&self) {
+        // Cloned once per run, shared by every connection
+        let services = Arc::new(Services {
+            handlers: self.handlers.clone(),
+            authenticator: self.authenticator.clone(),
+        });
+        let mut connections = JoinSet::new(); // One task per client connection
 
         while self.is_running.load(Ordering::SeqCst) {
           tokio::select!{     // Allows waiting on multiple asynchronous operations simultaneously.
-            result= self.listener.accept() => {  // Asynchronously accepts new client connections.
+            (slot, result) = self.accept() => {  // Asynchronously accepts new client connections.
               match result{
                 Ok((stream, addr)) => {
+                    // Filtered clients are closed before they cost a task, a TLS handshake or a slot
+                    if !self.ip_filter.read().unwrap().allows(addr.ip()) {
+                        self.metrics.record_filtered_connection();
+                        warn!("Dropping connection from {}: address not allowed by the IP filter", addr);
+                        continue;
+                    }
+                    let admitted = match self.admission.admit(addr.ip(), slot) {
+                        Ok(admitted) => admitted,
+                        Err(refusal) => {
+                            self.refuse(stream, addr, refusal, &mut connections);
+                            continue;
+                        }
+                    };
                     info!("New client connected: {}", addr);
 
+                    if let Err(e) = stream.set_nodelay(self.config.listener.nodelay) {
+                        warn!("Failed to set TCP_NODELAY for {}: {}", addr, e);
+                    }
+
                     // Handle the client request
-                    let mut client = Client::new(stream);
+                    let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+                    let registration = self.registry.register(connection_id, addr, self.config.push_queue_size);
+                    let connection = registration.connection().clone();
+                    let services = services.clone();
+                    let config = self.config.clone();
+                    let metrics = self.metrics.clone();
+                    let rate_limits = ConnectionLimiter::new(addr.ip(), self.address_limits.clone());
+                    let shutdown = self.drain_signal.subscribe();
+                    let tls = self.tls.clone();
+
+                   // Everything logged for the connection, its requests included, carries its id and peer
+                   let span = info_span!(
+                       "connection",
+                       id = connection_id,
+                       peer = %addr,
+                       identity = tracing::field::Empty, // once the client certificate or an Authenticate is verified
+                   );
 
                    // Spawns a new asynchronous task to handle each client connection
-                   tokio::spawn(async move {
-                            if let Err(e) = client.handle().await {
-                                error!("Error handling client {}: {}", addr, e);
+                   let task = connections.spawn(async move {
+                            // The TLS handshake runs here so a slow client cannot hold up the accept loop
+                            match secure(stream, tls.as_ref(), &config, &metrics).await {
+                                Ok((stream, identity)) => {
+                                    if let Some(identity) = identity {
+                                        Span::current().record("identity", identity.as_str());
+                                        info!("Client {} authenticated as {}", addr, identity);
+                                        registration.connection().set_identity(identity);
+                                    }
+                                    let client =
+                                        Client::new(stream, services, config, metrics, rate_limits, registration, shutdown);
+                                    if let Err(e) = client.handle().await {
+                                        error!("Error handling client {}: {}", addr, e);
+                                    }
+                                }
+                                Err(e) => warn!("TLS handshake with {} failed: {}", addr, e),
                             }
-                    });
+                            drop(admitted); // frees the connection slot
+                    }.instrument(span));
+                   connection.set_task(task); // lets `disconnect` abort it
                 }
-                
+
                 Err(e) => {
                     error!("Error accepting connection: {}", e);
                 }
-              } 
-            } 
-            
+              }
+            }
+
+            // Reaps finished connection tasks so the set does not grow without bound.
+            Some(result) = connections.join_next(), if !connections.is_empty() => {
+                // Cancelled tasks are connections closed by `disconnect`
+                if let Err(e) = result {
+                    if !e.is_cancelled() {
+                        error!("Connection task failed: {}", e);
+                    }
+                }
+            }
+
             // Listens for a shutdown signal and exits the loop when notified.
             _ = self.shutdown_notify.notified() => {
                 info!("Shutdown signal received. Stopping server.");
@@ -175,20 +1135,82 @@ impl Server {
           }
         }
 
-        info!("Server stopped.");
-        Ok(())
+        self.drain(connections).await;
     }
 
-    /// Stops the server by setting the `is_running` flag to `false`
+    /// Waits for a free connection slot under the queue policy, then for the next client
+    async fn accept(&self) -> (Option<OwnedSemaphorePermit>, io::Result<(TcpStream, SocketAddr)>) {
+        // Extra clients wait in the listen backlog until a slot frees up
+        let slot = self.admission.wait_for_slot().await;
+        (slot, self.listener.accept().await)
+    }
+
+    /// Closes a connection over a limit, first sending an error response unless the policy is to drop it.
+    ///
+    /// Over TLS the connection is closed straight away: a handshake per refused client would
+    /// tie up a task and a socket for as long as the limit is meant to spare them.
+    fn refuse(&self, mut stream: TcpStream, addr: SocketAddr, refusal: Refusal, connections: &mut JoinSet<()>) {
+        self.metrics.record_refused_connection();
+        if self.admission.policy() == ConnectionLimitPolicy::Drop || self.tls.is_some() {
+            warn!("Dropping connection from {}: {}", addr, refusal);
+            return;
+        }
+
+        warn!("Rejecting connection from {}: {}", addr, refusal);
+        let payload = encode_frame(&HandlerError::new(ErrorCode::TooManyConnections, refusal.to_string()).into_response());
+        let write_timeout = self.config.write_timeout;
+        connections.spawn(async move {
+            let _ = with_deadline(write_timeout, async move {
+                stream.write_all(&payload).await?;
+                stream.shutdown().await
+            })
+            .await;
+        });
+    }
+
+    /// Tells every connection to close and waits for them, aborting those still open after the drain timeout
+    async fn drain(&self, mut connections: JoinSet<()>) {
+        self.drain_signal.send_replace(true);
+        if connections.is_empty() {
+            return;
+        }
+
+        info!("Draining {} open connection(s).", connections.len());
+        let drained = tokio::time::timeout(self.config.drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!(
+                "{} connection(s) still open after {} ms. Aborting them.",
+                connections.len(),
+                self.config.drain_timeout.as_millis()
+            );
+            connections.abort_all();
+            while connections.join_next().await.is_some() {}
+        }
+    }
+
+    /// Stops the server and resolves once every connection has closed or been aborted
+    pub async fn shutdown(&self) {
+        let mut serving = self.serving.subscribe();
+        self.stop();
+        // An error means the sender is gone, which cannot happen while `self` is borrowed
+        let _ = serving.wait_for(|serving| !*serving).await;
+    }
+
+    /// Stops the server by setting the `is_running` flag to `false`.
+    ///
+    /// `run` then drains the open connections before it returns; use
+    /// [`Server::shutdown`] to wait for that.
     pub fn stop(&self) {
         if self.is_running.load(Ordering::SeqCst) {
             self.is_running.store(false, Ordering::SeqCst);  // Updating the is_running flag to false
-            self.shutdown_notify.notify_waiters(); // Notifies all tasks that are waiting for the shutdown signal.
+            self.shutdown_notify.notify_one(); // Wakes the accept loop, or leaves a permit if it is not waiting yet.
             info!("Shutdown signal sent.");
         } else {
             warn!("Server was already stopped or not running.");
         }
     }
 }
-
-
@@ -0,0 +1,180 @@
+use crate::config::TlsConfig;
+use std::{io, path::Path, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        server::WebPkiClientVerifier,
+        RootCertStore,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// A byte stream a connection is served over: plain TCP, or TLS on top of it
+pub(crate) trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+/// Builds the server's acceptor from the certificate chain and key named in `config`,
+/// requiring client certificates when it names a client CA bundle
+pub(crate) fn acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert_path)?;
+    let key = load_private_key(&config.key_path)?;
+    let builder = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_input)?;
+    let builder = match &config.client_ca_path {
+        Some(ca_path) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca_path)?), provider())
+                .build()
+                .map_err(invalid_input)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = builder.with_single_cert(certs, key).map_err(invalid_input)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Wraps an accepted connection in TLS when the server has an acceptor, returning
+/// it with the identity of the client's certificate, if the client presented one.
+///
+/// The handshake must complete within `timeout`; without an acceptor the stream is used as is.
+pub(crate) async fn accept(
+    acceptor: Option<&TlsAcceptor>,
+    stream: TcpStream,
+    timeout: Duration,
+) -> io::Result<(Box<dyn Transport>, Option<String>)> {
+    let Some(acceptor) = acceptor else {
+        return Ok((Box::new(stream), None));
+    };
+    let stream = match tokio::time::timeout(timeout, acceptor.accept(stream)).await {
+        Ok(stream) => stream?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake not completed in time")),
+    };
+    let identity = stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()).and_then(certificate_identity);
+    Ok((Box::new(stream), identity))
+}
+
+/// Who a certificate was issued to: its first DNS, URI or email SAN, or else the Common Name of its subject
+pub fn certificate_identity(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    let san = cert.subject_alternative_name().ok().flatten().and_then(|san| {
+        san.value.general_names.iter().find_map(|name| match name {
+            GeneralName::DNSName(name) | GeneralName::URI(name) | GeneralName::RFC822Name(name) => {
+                Some(name.to_string())
+            }
+            _ => None,
+        })
+    });
+    san.or_else(|| {
+        let cn = cert.subject().iter_common_name().next()?;
+        cn.as_str().ok().map(str::to_string)
+    })
+}
+
+/// TLS settings of the async client: the CA certificates it trusts, the name
+/// the server's certificate must be issued for and, for servers that require
+/// one, the client's own certificate
+#[derive(Clone)]
+pub struct ClientTls {
+    roots: Arc<RootCertStore>,
+    server_name: ServerName<'static>,
+    client_cert: Option<Arc<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>>,
+}
+
+impl ClientTls {
+    /// Trusts only the CA certificates in the PEM file at `ca_path`, e.g. a private CA or a self-signed server certificate
+    pub fn new(ca_path: impl AsRef<Path>, server_name: &str) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(invalid_input)?;
+        Ok(ClientTls {
+            roots: Arc::new(load_roots(ca_path)?),
+            server_name,
+            client_cert: None,
+        })
+    }
+
+    /// Presents the certificate chain and key in these PEM files to servers that require client certificates
+    pub fn with_client_cert(mut self, cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> io::Result<Self> {
+        let certs = load_certs(cert_path)?;
+        let key = load_private_key(key_path)?;
+        self.client_cert = Some(Arc::new((certs, key)));
+        Ok(self)
+    }
+
+    /// Performs the handshake over `stream`, verifying the server's certificate
+    pub(crate) async fn connect(&self, stream: TcpStream) -> io::Result<Box<dyn Transport>> {
+        let client_config = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_root_certificates(self.roots.clone());
+        let client_config = match &self.client_cert {
+            Some(client_cert) => {
+                let (certs, key) = client_cert.as_ref();
+                client_config
+                    .with_client_auth_cert(certs.clone(), key.clone_key())
+                    .map_err(invalid_input)?
+            }
+            None => client_config.with_no_client_auth(),
+        };
+        let connector = TlsConnector::from(Arc::new(client_config));
+        Ok(Box::new(connector.connect(self.server_name.clone(), stream).await?))
+    }
+}
+
+/// Reads every certificate of a PEM file
+pub fn load_certs(path: impl AsRef<Path>) -> io::Result<Vec<CertificateDer<'static>>> {
+    let path = path.as_ref();
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificate found in {}", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+/// Reads the CA certificates of a PEM bundle into a trust store
+fn load_roots(path: impl AsRef<Path>) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(invalid_input)?;
+    }
+    Ok(roots)
+}
+
+/// Reads the first private key of a PEM file
+pub fn load_private_key(path: impl AsRef<Path>) -> io::Result<PrivateKeyDer<'static>> {
+    let path = path.as_ref();
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+/// The ring provider, named explicitly so a second provider enabled elsewhere in the build cannot make rustls ambiguous
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> io::Error {
+    match e {
+        rustls::pki_types::pem::Error::Io(e) => {
+            io::Error::new(e.kind(), format!("Failed to read {}: {}", path.display(), e))
+        }
+        e => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid PEM in {}: {:?}", path.display(), e),
+        ),
+    }
+}
+
+fn invalid_input(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
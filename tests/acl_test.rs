@@ -0,0 +1,103 @@
+use embedded_recruitment_task::{
+    auth::StaticTokenAuthenticator,
+    client::{Client, ClientError},
+    config::{AclConfig, AclDefault, ServerConfig},
+    handler::MessageKind,
+    message::ErrorCode,
+    server::Server,
+};
+
+mod common;
+
+use common::start_server;
+
+fn assert_denied<T: std::fmt::Debug>(result: Result<T, ClientError>) {
+    match result {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::PermissionDenied),
+        other => panic!("Expected a PERMISSION_DENIED error, got {:?}", other),
+    }
+}
+
+
+// this test loads an ACL from the configuration: everyone may echo, only admin
+// identities may add, and kinds without a rule are closed
+
+#[tokio::test]
+async fn test_acl_from_config() {
+    let config = ServerConfig::from_toml_str(
+        r#"
+        bind_addr = "localhost:0"
+
+        [acl]
+        default = "deny"
+        messages.echo_message = ["*"]
+        messages.add_request = ["admin-*", "device-42"]
+        "#,
+    )
+    .expect("Failed to parse configuration");
+    assert_eq!(config.acl.default, AclDefault::Deny);
+
+    let tokens = [
+        ("admin-1".to_string(), "admin-secret".to_string()),
+        ("device-42".to_string(), "device-secret".to_string()),
+        ("device-43".to_string(), "other-secret".to_string()),
+    ];
+    let server = Server::builder()
+        .config(config)
+        .authenticator(StaticTokenAuthenticator::new(tokens).unwrap())
+        .build()
+        .await
+        .expect("Failed to start server");
+    let (server, handle) = start_server(server);
+    let addr = server.local_addr().unwrap();
+
+    let admin = Client::connect(addr).await.unwrap();
+    admin.authenticate("admin-secret").await.unwrap();
+    assert_eq!(admin.echo("hi").await.unwrap(), "hi");
+    assert_eq!(admin.add(1, 2).await.unwrap().result, 3);
+
+    let device = Client::connect(addr).await.unwrap();
+    device.authenticate("device-secret").await.unwrap();
+    assert_eq!(device.add(2, 2).await.unwrap().result, 4);
+
+    let other = Client::connect(addr).await.unwrap();
+    other.authenticate("other-secret").await.unwrap();
+    assert_eq!(other.echo("hi").await.unwrap(), "hi");
+    assert_denied(other.add(1, 2).await);
+    assert_denied(other.subscribe("devices/#").await);
+    assert!(other.is_connected(), "A denied request should leave the connection open");
+    assert_eq!(other.echo("still here").await.unwrap(), "still here");
+
+    assert_eq!(server.metrics().denied_requests(), 2);
+    assert_eq!(server.metrics().requests(MessageKind::AddRequest), 3);
+
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+// this test checks that without authentication only the "*" pattern matches,
+// and that kinds without a rule stay open by default
+
+#[tokio::test]
+async fn test_acl_anonymous_connections() {
+    let acl = AclConfig::default()
+        .allow(MessageKind::EchoMessage, ["*"])
+        .allow(MessageKind::Publish, ["gateway"]);
+    let server = Server::builder()
+        .bind("localhost:0")
+        .acl(acl)
+        .build()
+        .await
+        .expect("Failed to start server");
+    let (server, handle) = start_server(server);
+
+    let client = Client::connect(server.local_addr().unwrap()).await.unwrap();
+    assert_eq!(client.echo("hi").await.unwrap(), "hi");
+    assert_eq!(client.add(1, 1).await.unwrap().result, 2);
+    assert_eq!(client.subscribe("devices/#").await.unwrap(), 0);
+    assert_denied(client.publish("devices/1", b"on", false).await);
+
+    server.stop();
+    handle.await.unwrap();
+}
@@ -0,0 +1,370 @@
+use async_trait::async_trait;
+use embedded_recruitment_task::{
+    client::{Client, ClientError},
+    config::SlowConsumerPolicy,
+    handshake::PROTOCOL_VERSION,
+    handler::{ConnectionContext, Handler, HandlerError, MessageKind},
+    message::{client_message, server_message, AddOutcome, EchoMessage, ErrorCode, ServerMessage},
+    server::Server,
+};
+use std::time::{Duration, Instant};
+
+mod common;
+
+use common::start_server;
+
+
+// this test drives the typed helpers of the async client against a real server
+
+#[tokio::test]
+async fn test_async_client_echo_and_add() {
+    let server = Server::new("localhost:0").await.expect("Failed to start server");
+    let addr = server.local_addr().unwrap();
+    let (server, handle) = start_server(server);
+
+    let client = Client::connect(addr).await.expect("Failed to connect to the server");
+
+    // Connecting performs the handshake
+    assert_eq!(client.server_info().protocol_version, PROTOCOL_VERSION);
+    assert_eq!(
+        client.server_info().supported_messages,
+        vec!["echo_message", "add_request", "subscribe", "unsubscribe", "publish"]
+    );
+
+    assert_eq!(client.echo("Hello, World!").await.unwrap(), "Hello, World!");
+    assert_eq!(client.echo(&"y".repeat(3000)).await.unwrap().len(), 3000);
+
+    let add_response = client.add(10, 20).await.unwrap();
+    assert_eq!(add_response.result, 30);
+    assert_eq!(add_response.outcome(), AddOutcome::Exact);
+
+    // An ErrorResponse becomes a typed error and leaves the connection usable
+    match client.add(i32::MAX, 1).await {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::ArithmeticOverflow),
+        other => panic!("Expected an ARITHMETIC_OVERFLOW error, got {:?}", other),
+    }
+    assert!(client.is_connected());
+    assert_eq!(client.echo("after error").await.unwrap(), "after error");
+
+    // A request above the server's frame limit fails at once instead of timing out
+    let started = Instant::now();
+    match client.echo(&"z".repeat(5000)).await {
+        Err(ClientError::RequestTooLarge { len, max }) => {
+            assert!(len > 5000);
+            assert_eq!(max, 4096);
+        }
+        other => panic!("Expected a RequestTooLarge error, got {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_millis(500), "The request should not wait for an answer");
+    assert_eq!(client.echo("after too large").await.unwrap(), "after too large");
+
+    client.close().await.expect("Failed to close the connection");
+
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+// handler that never answers in time
+
+struct StuckHandler;
+
+#[async_trait]
+impl Handler for StuckHandler {
+    async fn handle(
+        &self,
+        _message: client_message::Message,
+        _ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        Err(HandlerError::new(ErrorCode::UnknownError, "too late"))
+    }
+}
+
+
+// this test checks that a call gives up after the per-call timeout
+
+#[tokio::test]
+async fn test_async_client_timeout() {
+    let server = Server::builder()
+        .bind("localhost:0")
+        .handler(MessageKind::EchoMessage, StuckHandler)
+        .drain_timeout(Duration::from_millis(100))
+        .build()
+        .await
+        .expect("Failed to start server");
+    let addr = server.local_addr().unwrap();
+    let (server, handle) = start_server(server);
+
+    let client = Client::connect_with_timeout(addr, Duration::from_millis(200))
+        .await
+        .expect("Failed to connect to the server");
+
+    match client.echo("anyone there?").await {
+        Err(ClientError::Timeout(limit)) => assert_eq!(limit, Duration::from_millis(200)),
+        other => panic!("Expected a timeout, got {:?}", other),
+    }
+
+    // Responses are matched by id, so the connection stays usable and the late response is discarded
+    assert!(client.is_connected());
+    assert_eq!(client.add(1, 2).await.unwrap().result, 3);
+
+    server.shutdown().await;
+    handle.await.unwrap();
+}
+
+
+// handler that waits for the number of milliseconds given as the content before echoing it
+
+struct DelayHandler;
+
+#[async_trait]
+impl Handler for DelayHandler {
+    async fn handle(
+        &self,
+        message: client_message::Message,
+        _ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        let client_message::Message::EchoMessage(echo) = message else {
+            return Err(HandlerError::new(ErrorCode::UnsupportedMessage, "Expected EchoMessage"));
+        };
+        let delay = echo.content.parse().unwrap_or(0);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        Ok(ServerMessage {
+            message: Some(server_message::Message::EchoMessage(EchoMessage { content: echo.content })),
+            ..Default::default()
+        })
+    }
+}
+
+
+// this test sends concurrent requests on one connection and checks that a fast
+// request is answered before a slow one sent earlier
+
+#[tokio::test]
+async fn test_async_client_concurrent_requests() {
+    let server = Server::builder()
+        .bind("localhost:0")
+        .handler(MessageKind::EchoMessage, DelayHandler)
+        .build()
+        .await
+        .expect("Failed to start server");
+    let addr = server.local_addr().unwrap();
+    let (server, handle) = start_server(server);
+
+    let client = Client::connect(addr).await.expect("Failed to connect to the server");
+    let started = Instant::now();
+
+    let timed = |content: &'static str| {
+        let client = &client;
+        async move {
+            let echoed = client.echo(content).await.expect("Failed to echo");
+            (echoed, Instant::now())
+        }
+    };
+    let ((slow, slow_done), (fast, fast_done), sum) =
+        tokio::join!(timed("400"), timed("20"), client.add(2, 3));
+
+    assert_eq!(slow, "400");
+    assert_eq!(fast, "20");
+    assert_eq!(sum.unwrap().result, 5);
+    assert!(fast_done < slow_done, "The fast request should not wait for the slow one");
+    assert!(
+        started.elapsed() < Duration::from_millis(800),
+        "Requests on one connection should be handled concurrently"
+    );
+
+    client.close().await.expect("Failed to close the connection");
+
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+fn echo_push(content: &str) -> ServerMessage {
+    ServerMessage {
+        message: Some(server_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        })),
+        ..Default::default()
+    }
+}
+
+async fn next_push(pushes: &mut tokio::sync::mpsc::Receiver<ServerMessage>) -> String {
+    let push = tokio::time::timeout(Duration::from_secs(2), pushes.recv())
+        .await
+        .expect("No push received in time")
+        .expect("Push channel closed");
+    assert!(push.push, "Pushed messages should be flagged");
+    assert_eq!(push.request_id, 0);
+    match push.message {
+        Some(server_message::Message::EchoMessage(echo)) => echo.content,
+        other => panic!("Expected an EchoMessage push, got {:?}", other),
+    }
+}
+
+
+// this test pushes messages to every client and to a single one, and checks
+// that requests keep being answered alongside the pushes
+
+#[tokio::test]
+async fn test_async_client_pushes() {
+    let server = Server::new("localhost:0").await.expect("Failed to start server");
+    let addr = server.local_addr().unwrap();
+    let (server, handle) = start_server(server);
+
+    let mut first = Client::connect(addr).await.expect("Failed to connect to the server");
+    let mut second = Client::connect(addr).await.expect("Failed to connect to the server");
+    let mut first_pushes = first.take_pushes().expect("Pushes already taken");
+    let mut second_pushes = second.take_pushes().expect("Pushes already taken");
+    assert!(first.take_pushes().is_none());
+
+    assert_eq!(server.broadcast(echo_push("to everyone")), 2);
+    assert_eq!(next_push(&mut first_pushes).await, "to everyone");
+    assert_eq!(next_push(&mut second_pushes).await, "to everyone");
+
+    let first_id = server.connections()[0].id;
+    assert!(server.send_to(first_id, echo_push("just for you")));
+    assert!(!server.send_to(u64::MAX, echo_push("nobody")));
+    assert_eq!(first.echo("a request").await.unwrap(), "a request");
+    assert_eq!(next_push(&mut first_pushes).await, "just for you");
+    assert!(second_pushes.try_recv().is_err(), "Only the first client should get the message");
+
+    assert_eq!(server.metrics().pushed_messages(), 3);
+
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+// this test routes publications through wildcard subscriptions, delivers the
+// retained value on subscribe and stops after unsubscribing
+
+#[tokio::test]
+async fn test_async_client_pubsub() {
+    let server = Server::new("localhost:0").await.expect("Failed to start server");
+    let addr = server.local_addr().unwrap();
+    let (server, handle) = start_server(server);
+
+    let publisher = Client::connect(addr).await.expect("Failed to connect to the server");
+    let mut subscriber = Client::connect(addr).await.expect("Failed to connect to the server");
+    let mut publications = subscriber.take_pushes().expect("Pushes already taken");
+
+    // Published before anyone subscribed: only the retained value is kept
+    assert_eq!(publisher.publish("devices/1/temperature", b"20", true).await.unwrap(), 0);
+    assert_eq!(publisher.publish("devices/1/humidity", b"40", false).await.unwrap(), 0);
+
+    assert_eq!(subscriber.subscribe("devices/+/temperature").await.unwrap(), 1);
+    assert_eq!(next_publication(&mut publications).await, ("devices/1/temperature".to_string(), b"20".to_vec(), true));
+
+    // A second matching filter still delivers each publication once
+    assert_eq!(subscriber.subscribe("devices/#").await.unwrap(), 1);
+    assert_eq!(next_publication(&mut publications).await.0, "devices/1/temperature");
+    assert_eq!(publisher.publish("devices/2/temperature", b"21", false).await.unwrap(), 1);
+    assert_eq!(next_publication(&mut publications).await, ("devices/2/temperature".to_string(), b"21".to_vec(), false));
+    assert_eq!(publisher.publish("rooms/kitchen", b"on", false).await.unwrap(), 0);
+
+    subscriber.unsubscribe("devices/+/temperature").await.unwrap();
+    subscriber.unsubscribe("devices/#").await.unwrap();
+    assert_eq!(publisher.publish("devices/2/temperature", b"22", false).await.unwrap(), 0);
+
+    // An empty retained payload clears the topic's value
+    publisher.publish("devices/1/temperature", b"", true).await.unwrap();
+    assert_eq!(subscriber.subscribe("devices/#").await.unwrap(), 0);
+    assert!(publications.try_recv().is_err(), "No publication should be left");
+
+    for result in [
+        publisher.publish("devices/+/temperature", b"1", false).await.map(|_| ()),
+        publisher.subscribe("devices/#/temperature").await.map(|_| ()),
+        publisher.subscribe("").await.map(|_| ()),
+    ] {
+        match result {
+            Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::InvalidTopic),
+            other => panic!("Expected an INVALID_TOPIC error, got {:?}", other),
+        }
+    }
+
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+
+// this test fills the retained topics up to the configured limit and checks that
+// retaining on another topic is refused while known topics can still change
+
+#[tokio::test]
+async fn test_retained_topic_limit() {
+    let server = Server::builder()
+        .bind("localhost:0")
+        .max_retained_topics(2)
+        .build()
+        .await
+        .expect("Failed to start server");
+    let addr = server.local_addr().unwrap();
+    let (server, handle) = start_server(server);
+
+    let client = Client::connect(addr).await.expect("Failed to connect to the server");
+    client.publish("devices/1", b"a", true).await.unwrap();
+    client.publish("devices/2", b"b", true).await.unwrap();
+    match client.publish("devices/3", b"c", true).await {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::TooManyRetainedTopics),
+        other => panic!("Expected a TOO_MANY_RETAINED_TOPICS error, got {:?}", other),
+    }
+
+    // Unretained publications, updates and clears are not limited
+    client.publish("devices/3", b"c", false).await.unwrap();
+    client.publish("devices/1", b"a2", true).await.unwrap();
+    client.publish("devices/2", b"", true).await.unwrap();
+    client.publish("devices/3", b"c", true).await.unwrap();
+    assert_eq!(client.subscribe("devices/#").await.unwrap(), 2);
+
+    server.stop();
+    handle.await.unwrap();
+}
+
+// this test retains more topics than fit in a push queue and checks that a
+// subscriber gets all of them, without being treated as a slow consumer
+
+#[tokio::test]
+async fn test_retained_delivery_beyond_push_queue() {
+    let server = Server::builder()
+        .bind("localhost:0")
+        .push_queue_size(8)
+        .slow_consumer_policy(SlowConsumerPolicy::Disconnect)
+        .build()
+        .await
+        .expect("Failed to start server");
+    let addr = server.local_addr().unwrap();
+    let (server, handle) = start_server(server);
+
+    let publisher = Client::connect(addr).await.expect("Failed to connect to the server");
+    for i in 0..100 {
+        publisher.publish(&format!("devices/{}", i), b"on", true).await.unwrap();
+    }
+
+    let mut subscriber = Client::connect(addr).await.expect("Failed to connect to the server");
+    let mut publications = subscriber.take_pushes().expect("Pushes already taken");
+    assert_eq!(subscriber.subscribe("devices/#").await.unwrap(), 100);
+    for _ in 0..100 {
+        assert!(next_publication(&mut publications).await.2, "Expected a retained publication");
+    }
+    assert!(subscriber.is_connected());
+    assert_eq!(server.metrics().dropped_pushes(), 0);
+
+    server.stop();
+    handle.await.unwrap();
+}
+
+async fn next_publication(pushes: &mut tokio::sync::mpsc::Receiver<ServerMessage>) -> (String, Vec<u8>, bool) {
+    let push = tokio::time::timeout(Duration::from_secs(2), pushes.recv())
+        .await
+        .expect("No publication received in time")
+        .expect("Push channel closed");
+    match push.message {
+        Some(server_message::Message::Publication(publication)) => {
+            (publication.topic, publication.payload, publication.retained)
+        }
+        other => panic!("Expected a Publication, got {:?}", other),
+    }
+}
@@ -0,0 +1,219 @@
+use async_trait::async_trait;
+use embedded_recruitment_task::{
+    auth::{Authenticator, Credentials, StaticTokenAuthenticator},
+    client::{Client, ClientError},
+    handler::{ConnectionContext, Handler, HandlerError, MessageKind},
+    message::{
+        authenticate, client_message, server_message, Authenticate, EchoMessage, ErrorCode, HmacSignature,
+        ServerMessage,
+    },
+    server::Server,
+};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+mod common;
+
+use common::start_server;
+
+/// A token file in the temporary directory, removed when dropped
+struct TokenFile(PathBuf);
+
+impl TokenFile {
+    fn new(name: &str, contents: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("auth-test-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        TokenFile(path)
+    }
+}
+
+impl Drop for TokenFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+const TOKENS: &str = r#"
+"device-42" = "s3cret-42"
+"sensor-7" = "s3cret-7"
+"#;
+
+async fn create_auth_server(tokens: &TokenFile) -> (Arc<Server>, tokio::task::JoinHandle<()>) {
+    let server = Server::builder()
+        .bind("localhost:0")
+        .auth_token_file(&tokens.0)
+        .handler(MessageKind::EchoMessage, WhoAmIHandler)
+        .build()
+        .await
+        .expect("Failed to start server");
+    start_server(server)
+}
+
+fn assert_code<T: std::fmt::Debug>(result: Result<T, ClientError>, expected: ErrorCode) {
+    match result {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, expected),
+        other => panic!("Expected a {} error, got {:?}", expected.as_str_name(), other),
+    }
+}
+
+async fn wait_for_disconnect(client: &Client) {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while client.is_connected() {
+        assert!(Instant::now() < deadline, "The server should close the connection");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+
+// handler that answers an echo with the identity of the connection
+
+struct WhoAmIHandler;
+
+#[async_trait]
+impl Handler for WhoAmIHandler {
+    async fn handle(
+        &self,
+        _message: client_message::Message,
+        ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        Ok(ServerMessage {
+            message: Some(server_message::Message::EchoMessage(EchoMessage {
+                content: ctx.identity.clone().unwrap_or_default(),
+            })),
+            ..Default::default()
+        })
+    }
+}
+
+
+// this test refuses requests until a bearer token from the token file is
+// presented, and closes the connection of a client with a wrong token
+
+#[tokio::test]
+async fn test_token_authentication() {
+    let tokens = TokenFile::new("token", TOKENS);
+    let (server, handle) = create_auth_server(&tokens).await;
+    let addr = server.local_addr().unwrap();
+
+    let client = Client::connect(addr).await.expect("Failed to connect to the server");
+    assert_code(client.echo("who am I").await, ErrorCode::Unauthenticated);
+    assert_code(client.add(1, 2).await, ErrorCode::Unauthenticated);
+    assert!(client.is_connected(), "Requests before authenticating should not close the connection");
+
+    assert_eq!(client.authenticate("s3cret-42").await.unwrap(), "device-42");
+    assert_eq!(client.echo("who am I").await.unwrap(), "device-42");
+    assert_eq!(server.connections()[0].identity.as_deref(), Some("device-42"));
+
+    // The identity of a connection cannot change
+    assert_code(client.authenticate("s3cret-7").await, ErrorCode::UnsupportedMessage);
+    assert_eq!(client.echo("who am I").await.unwrap(), "device-42");
+
+    let guesser = Client::connect(addr).await.expect("Failed to connect to the server");
+    assert_code(guesser.authenticate("s3cret").await, ErrorCode::Unauthenticated);
+    wait_for_disconnect(&guesser).await;
+    assert_eq!(server.metrics().authentication_failures(), 1);
+
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+// this test authenticates by signing a challenge, and refuses wrong secrets,
+// unknown key ids and signatures sent without a challenge
+
+#[tokio::test]
+async fn test_hmac_authentication() {
+    let tokens = TokenFile::new("hmac", TOKENS);
+    let (server, handle) = create_auth_server(&tokens).await;
+    let addr = server.local_addr().unwrap();
+
+    let client = Client::connect(addr).await.expect("Failed to connect to the server");
+    assert_eq!(client.authenticate_hmac("sensor-7", b"s3cret-7").await.unwrap(), "sensor-7");
+    assert_eq!(client.echo("who am I").await.unwrap(), "sensor-7");
+
+    let wrong_secret = Client::connect(addr).await.expect("Failed to connect to the server");
+    assert_code(wrong_secret.authenticate_hmac("sensor-7", b"s3cret-42").await, ErrorCode::Unauthenticated);
+    wait_for_disconnect(&wrong_secret).await;
+
+    let unknown = Client::connect(addr).await.expect("Failed to connect to the server");
+    assert_code(unknown.authenticate_hmac("sensor-8", b"s3cret-7").await, ErrorCode::Unauthenticated);
+    wait_for_disconnect(&unknown).await;
+
+    // A signature only answers a challenge the server issued on this connection
+    let replayer = Client::connect(addr).await.expect("Failed to connect to the server");
+    let signature = HmacSignature {
+        key_id: "sensor-7".to_string(),
+        signature: vec![0; 32],
+    };
+    let message = client_message::Message::Authenticate(Authenticate {
+        credentials: Some(authenticate::Credentials::Hmac(signature)),
+    });
+    assert_code(replayer.request(message).await, ErrorCode::Unauthenticated);
+    wait_for_disconnect(&replayer).await;
+
+    assert_eq!(server.metrics().authentication_failures(), 3);
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+// authenticator that accepts any token starting with "user:" as that user
+
+struct PrefixAuthenticator;
+
+#[async_trait]
+impl Authenticator for PrefixAuthenticator {
+    async fn authenticate(&self, credentials: Credentials<'_>) -> Result<String, HandlerError> {
+        match credentials {
+            Credentials::Token(token) => token
+                .strip_prefix("user:")
+                .map(str::to_string)
+                .ok_or_else(|| HandlerError::new(ErrorCode::Unauthenticated, "Not a user token")),
+            Credentials::Hmac { .. } => Err(HandlerError::new(ErrorCode::Unauthenticated, "HMAC not supported")),
+        }
+    }
+}
+
+
+// this test plugs in a custom authenticator, checks that servers without one
+// do not require or accept authentication, and that a bad token file fails the build
+
+#[tokio::test]
+async fn test_custom_authenticator() {
+    let server = Server::builder()
+        .bind("localhost:0")
+        .authenticator(PrefixAuthenticator)
+        .build()
+        .await
+        .expect("Failed to start server");
+    let (server, handle) = start_server(server);
+    let client = Client::connect(server.local_addr().unwrap()).await.unwrap();
+    assert_code(client.echo("hi").await, ErrorCode::Unauthenticated);
+    assert_eq!(client.authenticate("user:alice").await.unwrap(), "alice");
+    assert_eq!(client.echo("hi").await.unwrap(), "hi");
+    server.stop();
+    handle.await.unwrap();
+
+    let server = Server::new("localhost:0").await.expect("Failed to start server");
+    let (server, handle) = start_server(server);
+    let client = Client::connect(server.local_addr().unwrap()).await.unwrap();
+    assert_eq!(client.echo("hi").await.unwrap(), "hi");
+    assert_code(client.authenticate("user:alice").await, ErrorCode::UnsupportedMessage);
+    assert!(client.is_connected());
+    server.stop();
+    handle.await.unwrap();
+
+    let missing = std::env::temp_dir().join("auth-test-missing-tokens.toml");
+    assert!(Server::builder().bind("localhost:0").auth_token_file(missing).build().await.is_err());
+    let empty_secret = TokenFile::new("empty", r#""device-42" = """#);
+    let result = Server::builder().bind("localhost:0").auth_token_file(&empty_secret.0).build().await;
+    assert!(result.is_err(), "An empty secret should fail the build");
+
+    // The same goes for secrets given in code
+    let secrets = [("device-42".to_string(), String::new()), ("sensor-7".to_string(), "s3cret-7".to_string())];
+    assert!(StaticTokenAuthenticator::new(secrets).is_err(), "An empty secret should be refused");
+    assert!(StaticTokenAuthenticator::new([("sensor-7".to_string(), "s3cret-7".to_string())]).is_ok());
+}
@@ -0,0 +1,182 @@
+use async_trait::async_trait;
+use embedded_recruitment_task::{
+    auth::StaticTokenAuthenticator,
+    blocking::Client,
+    error::ClientError,
+    handler::{ConnectionContext, Handler, HandlerError, MessageKind},
+    message::{client_message, server_message, AddOutcome, ErrorCode, ServerMessage},
+    server::Server,
+};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+mod common;
+
+use common::start_server;
+
+
+// this test drives the typed helpers of the blocking client against a real server
+
+#[test]
+fn test_blocking_client_echo_and_add() {
+    let runtime = Runtime::new().unwrap();
+    let server = runtime
+        .block_on(Server::new("localhost:0"))
+        .expect("Failed to start server");
+    let port = server.local_addr().unwrap().port();
+    let (server, handle) = runtime.block_on(async { start_server(server) });
+
+    let mut client = Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    assert_eq!(client.echo("Hello, World!").unwrap(), "Hello, World!");
+    assert_eq!(client.echo(&"y".repeat(3000)).unwrap().len(), 3000);
+
+    let add_response = client.add(10, 20).unwrap();
+    assert_eq!(add_response.result, 30);
+    assert_eq!(add_response.outcome(), AddOutcome::Exact);
+
+    // An ErrorResponse becomes a typed error and leaves the connection usable
+    match client.add(i32::MAX, 1) {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::ArithmeticOverflow),
+        other => panic!("Expected an ARITHMETIC_OVERFLOW error, got {:?}", other),
+    }
+    assert!(client.is_connected());
+    assert_eq!(client.echo("after error").unwrap(), "after error");
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(matches!(client.echo("closed"), Err(ClientError::Disconnected)));
+
+    server.stop();
+    runtime.block_on(handle).unwrap();
+}
+
+
+// this test publishes to its own subscription: the publication is buffered
+// while the blocking client waits for its responses
+
+#[test]
+fn test_blocking_client_pubsub() {
+    let runtime = Runtime::new().unwrap();
+    let server = runtime
+        .block_on(Server::new("localhost:0"))
+        .expect("Failed to start server");
+    let port = server.local_addr().unwrap().port();
+    let (server, handle) = runtime.block_on(async { start_server(server) });
+
+    let mut client = Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    assert_eq!(client.subscribe("alerts/#").unwrap(), 0);
+    assert_eq!(client.publish("alerts/disk", b"full", false).unwrap(), 1);
+
+    // Responses may overtake the push, so keep asking until it was read
+    let mut pushes = Vec::new();
+    for _ in 0..10 {
+        assert_eq!(client.echo("flush").unwrap(), "flush");
+        pushes.extend(client.take_pushes());
+        if !pushes.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(pushes.len(), 1);
+    match &pushes[0].message {
+        Some(server_message::Message::Publication(publication)) => {
+            assert_eq!(publication.topic, "alerts/disk");
+            assert_eq!(publication.payload, b"full");
+        }
+        other => panic!("Expected a Publication, got {:?}", other),
+    }
+
+    match client.publish("alerts/#", b"", false) {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::InvalidTopic),
+        other => panic!("Expected an INVALID_TOPIC error, got {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    server.stop();
+    runtime.block_on(handle).unwrap();
+}
+
+
+// handler that never answers in time
+
+struct StuckHandler;
+
+#[async_trait]
+impl Handler for StuckHandler {
+    async fn handle(
+        &self,
+        _message: client_message::Message,
+        _ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        Err(HandlerError::new(ErrorCode::UnknownError, "too late"))
+    }
+}
+
+
+// this test authenticates with a bearer token before its requests are served
+
+#[test]
+fn test_blocking_client_authenticate() {
+    let runtime = Runtime::new().unwrap();
+    let tokens = [("device-42".to_string(), "s3cret-42".to_string())];
+    let server = runtime
+        .block_on(
+            Server::builder()
+                .bind("localhost:0")
+                .authenticator(StaticTokenAuthenticator::new(tokens).unwrap())
+                .build(),
+        )
+        .expect("Failed to start server");
+    let port = server.local_addr().unwrap().port();
+    let (server, handle) = runtime.block_on(async { start_server(server) });
+
+    let mut client = Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    match client.echo("too early") {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::Unauthenticated),
+        other => panic!("Expected an UNAUTHENTICATED error, got {:?}", other),
+    }
+    assert_eq!(client.authenticate("s3cret-42").unwrap(), "device-42");
+    assert_eq!(client.echo("in").unwrap(), "in");
+
+    server.stop();
+    runtime.block_on(handle).unwrap();
+}
+
+
+// this test checks that the socket read timeout bounds a call, not just connecting
+
+#[test]
+fn test_blocking_client_read_timeout() {
+    let runtime = Runtime::new().unwrap();
+    let server = runtime
+        .block_on(
+            Server::builder()
+                .bind("localhost:0")
+                .handler(MessageKind::EchoMessage, StuckHandler)
+                .drain_timeout(Duration::from_millis(100))
+                .build(),
+        )
+        .expect("Failed to start server");
+    let port = server.local_addr().unwrap().port();
+    let (server, handle) = runtime.block_on(async { start_server(server) });
+
+    let mut client = Client::new("localhost", port.into(), 200);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    match client.echo("anyone there?") {
+        Err(ClientError::Timeout(limit)) => assert_eq!(limit, Duration::from_millis(200)),
+        other => panic!("Expected a timeout, got {:?}", other),
+    }
+
+    // The connection is dropped so a late response cannot be mistaken for the next one
+    assert!(!client.is_connected());
+    assert!(matches!(client.add(1, 2), Err(ClientError::Disconnected)));
+
+    runtime.block_on(server.shutdown());
+    runtime.block_on(handle).unwrap();
+}
@@ -0,0 +1,95 @@
+use embedded_recruitment_task::{
+    blocking::Client,
+    error::ClientError,
+    framing::{encode_frame, FrameBuffer},
+    handshake,
+    message::{
+        client_message, server_message, AddResponse, ClientMessage, EchoMessage, ErrorCode, ErrorResponse,
+        HelloAck, ServerMessage,
+    },
+};
+use prost::Message;
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener},
+    thread,
+};
+
+// Serves one connection with std only, so the blocking client is tested in builds without tokio:
+// echoes, adds, acknowledges a Hello and answers anything else with UNSUPPORTED_MESSAGE
+fn start_std_server() -> (SocketAddr, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut frames = FrameBuffer::new(4096);
+        let mut buffer = [0u8; 1024];
+        loop {
+            let frame = match frames.next_frame().unwrap() {
+                Some(frame) => frame,
+                None => match stream.read(&mut buffer).unwrap() {
+                    0 => return, // the client disconnected
+                    n => {
+                        frames.extend(&buffer[..n]);
+                        continue;
+                    }
+                },
+            };
+            let request = ClientMessage::decode(frame.as_slice()).unwrap();
+            let message = match request.message {
+                Some(client_message::Message::Hello(hello)) => server_message::Message::HelloAck(HelloAck {
+                    protocol_version: hello.protocol_version.min(handshake::PROTOCOL_VERSION),
+                    max_frame_size: 4096,
+                    supported_messages: vec!["echo_message".to_string(), "add_request".to_string()],
+                    features: Vec::new(),
+                }),
+                Some(client_message::Message::EchoMessage(echo)) => server_message::Message::EchoMessage(echo),
+                Some(client_message::Message::AddRequest(add)) => server_message::Message::AddResponse(AddResponse {
+                    result: add.a.wrapping_add(add.b),
+                    ..Default::default()
+                }),
+                _ => server_message::Message::ErrorResponse(ErrorResponse {
+                    code: ErrorCode::UnsupportedMessage.into(),
+                    message: "Unsupported message type".to_string(),
+                    request_id: request.request_id,
+                }),
+            };
+            let response = ServerMessage {
+                message: Some(message),
+                request_id: request.request_id,
+                ..Default::default()
+            };
+            stream.write_all(&encode_frame(&response)).unwrap();
+        }
+    });
+    (addr, handle)
+}
+
+
+// this test drives the blocking client against a std-only server
+
+#[test]
+fn test_blocking_client_without_tokio() {
+    let (addr, handle) = start_std_server();
+
+    let mut client = Client::new(&addr.ip().to_string(), addr.port().into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let ack = client.hello().expect("Handshake failed");
+    assert_eq!(ack.protocol_version, handshake::PROTOCOL_VERSION);
+    assert_eq!(client.echo("no runtime").unwrap(), "no runtime");
+    assert_eq!(client.add(2, 3).unwrap().result, 5);
+
+    // An ErrorResponse becomes a typed error and leaves the connection usable
+    match client.subscribe("alerts/#") {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::UnsupportedMessage),
+        other => panic!("Expected an UNSUPPORTED_MESSAGE error, got {:?}", other),
+    }
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "after error".to_string(),
+    });
+    assert!(matches!(client.request(message), Ok(server_message::Message::EchoMessage(_))));
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    handle.join().expect("The server thread panicked");
+}
@@ -1,5 +1,20 @@
+// The original tests build their messages field by field and clone them before sending
+#![allow(clippy::field_reassign_with_default, clippy::clone_on_copy)]
+
+use async_trait::async_trait;
 use embedded_recruitment_task::{
-    message::{client_message, server_message, AddRequest, EchoMessage},
+    config::{
+        ConnectionLimitPolicy, MessageRateLimit, OversizeFramePolicy, RateLimit, RateLimitConfig, ServerConfig,
+        SlowConsumerPolicy,
+    },
+    error::ClientError,
+    framing::encode_frame,
+    handshake,
+    message::{
+        client_message, server_message, AddOutcome, AddRequest, ClientMessage, EchoMessage, ErrorCode,
+        ServerMessage,
+    },
+    handler::{ConnectionContext, Handler, HandlerError, HandlerRegistry, MessageKind, OverflowPolicy},
     server::Server,
 };
 use std::{
@@ -328,6 +343,110 @@ fn test_client_add_request() {
 
 
 
+// this test sends sums outside the i32 range to servers configured with each
+// overflow policy and checks the result and outcome reported on the wire
+
+#[test]
+fn test_client_add_request_overflow_policies() {
+    let runtime = Runtime::new().unwrap();
+
+    let cases = [
+        (OverflowPolicy::Saturate, i32::MAX, 1, i32::MAX, AddOutcome::Saturated, 0),
+        (OverflowPolicy::Saturate, i32::MIN, -1, i32::MIN, AddOutcome::Saturated, 0),
+        (OverflowPolicy::Wrap, i32::MAX, 1, i32::MIN, AddOutcome::Wrapped, 0),
+        (OverflowPolicy::Widen, i32::MAX, 1, i32::MAX, AddOutcome::Widened, i64::from(i32::MAX) + 1),
+        (OverflowPolicy::Widen, 2, 3, 5, AddOutcome::Exact, 5),
+        (OverflowPolicy::Error, 2, 3, 5, AddOutcome::Exact, 0),
+    ];
+
+    for (policy, a, b, result, outcome, wide_result) in cases {
+        let server = runtime.block_on(async {
+            Arc::new(
+                Server::builder()
+                    .bind("localhost:0")
+                    .overflow_policy(policy)
+                    .build()
+                    .await
+                    .expect("Failed to start server"),
+            )
+        });
+        let port = server.local_addr().unwrap().port();
+        let handle = setup_server_thread(server.clone(), &runtime);
+
+        let mut client = client::Client::new("localhost", port.into(), 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+        let message = client_message::Message::AddRequest(AddRequest { a, b });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::AddResponse(add_response)) => {
+                assert_eq!(add_response.result, result, "{:?}: wrong result", policy);
+                assert_eq!(add_response.outcome(), outcome, "{:?}: wrong outcome", policy);
+                assert_eq!(add_response.wide_result, wide_result, "{:?}: wrong wide_result", policy);
+            }
+            _ => panic!("{:?}: expected AddResponse, but received a different message", policy),
+        }
+
+        assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+        server.stop();
+        runtime.block_on(async {
+            handle.await.unwrap();
+        });
+    }
+}
+
+
+
+// this test checks that the overflow policy and a custom AddRequest handler
+// do not undo each other, whatever order the builder calls come in
+
+#[test]
+fn test_overflow_policy_builder_order() {
+    let runtime = Runtime::new().unwrap();
+
+    let config = ServerConfig {
+        overflow_policy: OverflowPolicy::Wrap,
+        ..ServerConfig::default()
+    };
+    let builders = [
+        // The custom handler wins over a later config
+        (Server::builder().handler(MessageKind::AddRequest, ShoutHandler).config(config.clone()), None),
+        // A later registry keeps the built-in AddHandler, which follows the earlier policy
+        (
+            Server::builder()
+                .overflow_policy(OverflowPolicy::Wrap)
+                .handlers(HandlerRegistry::with_defaults()),
+            Some(i32::MIN),
+        ),
+    ];
+
+    for (builder, expected) in builders {
+        let server = runtime.block_on(async {
+            Arc::new(builder.bind("localhost:0").build().await.expect("Failed to start server"))
+        });
+        assert_eq!(server.config().overflow_policy, OverflowPolicy::Wrap);
+        let port = server.local_addr().unwrap().port();
+        let handle = setup_server_thread(server.clone(), &runtime);
+
+        let mut client = client::Client::new("localhost", port.into(), 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        match (client.add(i32::MAX, 1), expected) {
+            (Ok(add_response), Some(result)) => assert_eq!(add_response.result, result),
+            (Err(ClientError::Server { code, .. }), None) => assert_eq!(code, ErrorCode::UnsupportedMessage),
+            (other, _) => panic!("Unexpected answer to the AddRequest: {:?}", other),
+        }
+        assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+        server.stop();
+        runtime.block_on(async {
+            handle.await.unwrap();
+        });
+    }
+}
+
+
 // test ensures the server processes an AddRequest correctly and returns an appropriate AddResponse
 #[test]
 fn test_add_response() {
@@ -427,6 +546,69 @@ fn test_malformed_message() {
 
 
 
+// this test checks that undecodable frames, empty messages and oversized frames
+// are answered with an ErrorResponse instead of leaving the client waiting
+
+#[test]
+fn test_error_responses() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Set up the server
+    let server = create_server(&runtime);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // A frame whose payload declares a field longer than the frame itself
+    assert!(client.send_raw(&[2, 0x0a, 0x05]).is_ok(), "Failed to send frame");
+    expect_error(client.receive(), ErrorCode::DecodeError);
+
+    // A well-formed ClientMessage that carries no message at all
+    assert!(client.send_raw(&encode_frame(&ClientMessage::default())).is_ok(), "Failed to send frame");
+    expect_error(client.receive(), ErrorCode::UnsupportedMessage);
+
+    // An AddRequest whose sum does not fit in an i32
+    let message = client_message::Message::AddRequest(AddRequest { a: i32::MAX, b: 1 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(client.receive(), ErrorCode::ArithmeticOverflow);
+
+    // The connection is still usable after the errors above
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "still here".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "still here"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // A length prefix above the configured max_frame_size (DEFAULT_MAX_FRAME_SIZE, 4096) is rejected before the payload arrives
+    let mut prefix = Vec::new();
+    prost::encoding::encode_varint(1 << 20, &mut prefix);
+    assert!(client.send_raw(&prefix).is_ok(), "Failed to send frame prefix");
+    expect_error(client.receive(), ErrorCode::FrameTooLarge);
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+fn expect_error(response: std::io::Result<ServerMessage>, code: ErrorCode) {
+    match response.expect("Failed to receive ErrorResponse").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), code, "Unexpected error code: {}", error.message);
+        }
+        other => panic!("Expected ErrorResponse, but received {:?}", other),
+    }
+}
+
+
+
 // his test creates multiple clients, each sending requests to the server concurrently, 
 // and verifies that all responses are processed correctly.
 
@@ -543,3 +725,1154 @@ fn test_high_client_load() {
         handle.await.unwrap();
     });
 }
+
+
+
+// this test writes two requests in a single write and checks that the server
+// answers both, in order, instead of decoding them as one message
+
+#[test]
+fn test_pipelined_messages() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Set up the server
+    let server = create_server(&runtime);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Glue an EchoMessage frame and an AddRequest frame together
+    let mut payload = encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "first".to_string(),
+        })),
+        request_id: 0, // requests without an id are answered in order
+    });
+    payload.extend(encode_frame(&ClientMessage {
+        message: Some(client_message::Message::AddRequest(AddRequest { a: 1, b: 2 })),
+        request_id: 0,
+    }));
+    assert!(client.send_raw(&payload).is_ok(), "Failed to send pipelined frames");
+
+    match client.receive().expect("Failed to receive first response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "first"),
+        _ => panic!("Expected EchoMessage as the first response"),
+    }
+    match client.receive().expect("Failed to receive second response").message {
+        Some(server_message::Message::AddResponse(add_response)) => assert_eq!(add_response.result, 3),
+        _ => panic!("Expected AddResponse as the second response"),
+    }
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+
+// this test dribbles a single frame to the server a few bytes at a time
+
+#[test]
+fn test_split_message() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Set up the server
+    let server = create_server(&runtime);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let payload = encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "sent in pieces".to_string(),
+        })),
+        request_id: 0,
+    });
+    for chunk in payload.chunks(3) {
+        assert!(client.send_raw(chunk).is_ok(), "Failed to send partial frame");
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "sent in pieces"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+
+// this test echoes a message larger than a single client read buffer
+
+#[test]
+fn test_large_echo_message() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Set up the server
+    let server = create_server(&runtime);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let content = "x".repeat(3000);
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: content.clone(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, content),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+
+// handler that shouts the echoed content back, to check that registered handlers
+// replace the built-in ones
+
+struct ShoutHandler;
+
+#[async_trait]
+impl Handler for ShoutHandler {
+    async fn handle(
+        &self,
+        message: client_message::Message,
+        ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        match message {
+            client_message::Message::EchoMessage(echo) => Ok(ServerMessage {
+                message: Some(server_message::Message::EchoMessage(EchoMessage {
+                    content: format!("{} from {}", echo.content.to_uppercase(), ctx.peer_addr.ip()),
+                })),
+                ..Default::default()
+            }),
+            _ => Err(HandlerError::new(ErrorCode::UnsupportedMessage, "Expected EchoMessage")),
+        }
+    }
+}
+
+#[test]
+fn test_custom_handler() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Only the echo handler is registered, and it is a custom one
+    let mut handlers = HandlerRegistry::new();
+    handlers.register(MessageKind::EchoMessage, ShoutHandler);
+    let server = runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .handlers(handlers)
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    });
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "hello".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert!(echo.content.starts_with("HELLO from "), "Unexpected echo: {}", echo.content)
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Nothing is registered for AddRequest any more
+    let message = client_message::Message::AddRequest(AddRequest { a: 1, b: 2 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(client.receive(), ErrorCode::UnsupportedMessage);
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+
+// this test builds a server with a small frame limit and an idle timeout and
+// checks that both are enforced
+
+#[test]
+fn test_server_builder_limits() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let server = runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .max_frame_size(64)
+                .idle_timeout(Some(std::time::Duration::from_millis(200)))
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    });
+    assert_eq!(server.config().max_frame_size, 64);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    // A 100 byte echo no longer fits in a frame
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "x".repeat(100),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(client.receive(), ErrorCode::FrameTooLarge);
+    assert!(client.receive().is_err(), "Oversized frame should close the connection by default");
+    assert_eq!(server.metrics().oversize_frames(), 1);
+
+    // A silent client is disconnected once the idle timeout expires
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    std::thread::sleep(std::time::Duration::from_millis(400));
+    assert!(client.receive().is_err(), "Idle connection should have been closed");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+
+// handler that takes a while to echo, to keep a request in flight during shutdown
+
+struct SlowEchoHandler(std::time::Duration);
+
+#[async_trait]
+impl Handler for SlowEchoHandler {
+    async fn handle(
+        &self,
+        message: client_message::Message,
+        _ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        tokio::time::sleep(self.0).await;
+        Ok(ServerMessage {
+            message: Some(match message {
+                client_message::Message::EchoMessage(echo) => server_message::Message::EchoMessage(echo),
+                _ => return Err(HandlerError::new(ErrorCode::UnsupportedMessage, "Expected EchoMessage")),
+            }),
+            ..Default::default()
+        })
+    }
+}
+
+fn create_slow_server(runtime: &Runtime, delay_ms: u64, drain_timeout_ms: u64) -> Arc<Server> {
+    runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .handler(
+                    MessageKind::EchoMessage,
+                    SlowEchoHandler(std::time::Duration::from_millis(delay_ms)),
+                )
+                .drain_timeout(std::time::Duration::from_millis(drain_timeout_ms))
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    })
+}
+
+
+// this test shuts the server down while a request is being handled and checks
+// that the response is still delivered before the connection is closed
+
+#[test]
+fn test_shutdown_drains_in_flight_requests() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_slow_server(&runtime, 300, 5000);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "in flight".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    std::thread::sleep(std::time::Duration::from_millis(50)); // let the handler start
+
+    // Resolves only once the connection has finished and closed
+    runtime.block_on(server.shutdown());
+
+    match client.receive().expect("In-flight request was not answered").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "in flight"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    assert!(client.receive().is_err(), "Connection should be closed after the drain");
+
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test checks that connections still busy after the drain timeout are aborted
+
+#[test]
+fn test_shutdown_aborts_after_drain_timeout() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_slow_server(&runtime, 10_000, 200);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "never answered".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    std::thread::sleep(std::time::Duration::from_millis(50)); // let the handler start
+
+    let started = std::time::Instant::now();
+    runtime.block_on(server.shutdown());
+    assert!(
+        started.elapsed() < std::time::Duration::from_secs(2),
+        "Shutdown took {:?}, the stuck connection was not aborted",
+        started.elapsed()
+    );
+    assert!(client.receive().is_err(), "Aborted connection should be closed");
+
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test opens a connection with a Hello and checks what the server agrees to
+
+#[test]
+fn test_handshake() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_server(&runtime);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let ack = client.hello().expect("Handshake failed");
+    assert_eq!(ack.protocol_version, handshake::PROTOCOL_VERSION);
+    assert_eq!(ack.max_frame_size, 4096);
+    assert_eq!(
+        ack.supported_messages,
+        vec!["echo_message", "add_request", "subscribe", "unsubscribe", "publish"]
+    );
+    assert_eq!(ack.features, vec![handshake::FEATURE_PIPELINING]);
+
+    // Requests work as usual after the handshake, but a second Hello is refused
+    assert_eq!(client.echo("after hello").unwrap(), "after hello");
+    match client.hello() {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::UnsupportedMessage),
+        other => panic!("Expected UNSUPPORTED_MESSAGE for a second Hello, got {:?}", other),
+    }
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test opens with a Hello that asks for no features and a small frame limit, and
+// checks that requests with an id are answered in order and large responses are refused
+
+#[test]
+fn test_handshake_enforced() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_slow_server(&runtime, 200, 1000);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let mut hello = handshake::client_hello(256);
+    hello.features.clear();
+    assert!(client.send(client_message::Message::Hello(hello)).is_ok(), "Failed to send Hello");
+    match client.receive().expect("Failed to receive HelloAck").message {
+        Some(server_message::Message::HelloAck(ack)) => assert!(ack.features.is_empty()),
+        _ => panic!("Expected HelloAck"),
+    }
+
+    // The slow echo is sent first, so without pipelining it is answered first
+    let mut payload = encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "slow".to_string(),
+        })),
+        request_id: 1,
+    });
+    payload.extend(encode_frame(&ClientMessage {
+        message: Some(client_message::Message::AddRequest(AddRequest { a: 1, b: 2 })),
+        request_id: 2,
+    }));
+    assert!(client.send_raw(&payload).is_ok(), "Failed to send pipelined frames");
+
+    let first = client.receive().expect("Failed to receive first response");
+    assert_eq!(first.request_id, 1);
+    assert!(matches!(first.message, Some(server_message::Message::EchoMessage(_))));
+    let second = client.receive().expect("Failed to receive second response");
+    assert_eq!(second.request_id, 2);
+    assert!(matches!(second.message, Some(server_message::Message::AddResponse(_))));
+
+    // A response over the client's 256 bytes is replaced by an error, and the connection stays open
+    let large = encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "x".repeat(300),
+        })),
+        request_id: 3,
+    });
+    assert!(client.send_raw(&large).is_ok(), "Failed to send frame");
+    match client.receive().expect("Failed to receive error").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::FrameTooLarge);
+            assert_eq!(error.request_id, 3);
+        }
+        _ => panic!("Expected FRAME_TOO_LARGE for a response over the client's limit"),
+    }
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "small".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "small"),
+        _ => panic!("Expected EchoMessage"),
+    }
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test checks that a client speaking an unknown protocol version is told so and disconnected
+
+#[test]
+fn test_handshake_incompatible_version() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_server(&runtime);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let mut hello = handshake::client_hello(64 * 1024);
+    hello.protocol_version = 0;
+    assert!(client.send(client_message::Message::Hello(hello)).is_ok(), "Failed to send Hello");
+    expect_error(client.receive(), ErrorCode::IncompatibleVersion);
+    assert!(client.receive().is_err(), "Incompatible client should be disconnected");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test checks that a server requiring the handshake refuses clients that skip it
+
+#[test]
+fn test_handshake_required() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .require_handshake(true)
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    });
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "no hello".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    expect_error(client.receive(), ErrorCode::HandshakeRequired);
+    assert!(client.receive().is_err(), "Client without a handshake should be disconnected");
+
+    assert!(client.connect().is_ok(), "Failed to reconnect to the server");
+    assert!(client.hello().is_ok(), "Handshake failed");
+    assert_eq!(client.echo("with hello").unwrap(), "with hello");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test checks that with the skip policy an oversized frame is answered with
+// an error and its bytes are dropped, while the connection keeps working
+
+#[test]
+fn test_oversize_frame_skip_policy() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .max_frame_size(64)
+                .oversize_frame_policy(OversizeFramePolicy::Skip)
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    });
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // The oversized frame arrives in two pieces, the second glued to a valid frame
+    let oversized = encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "x".repeat(200),
+        })),
+        request_id: 0,
+    });
+    let mut rest = oversized[100..].to_vec();
+    rest.extend(encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "still here".to_string(),
+        })),
+        request_id: 0,
+    }));
+
+    assert!(client.send_raw(&oversized[..100]).is_ok(), "Failed to send first piece");
+    expect_error(client.receive(), ErrorCode::FrameTooLarge);
+    assert!(client.send_raw(&rest).is_ok(), "Failed to send second piece");
+    match client.receive().expect("Failed to receive echo").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "still here"),
+        other => panic!("Expected EchoMessage after the skipped frame, got {:?}", other),
+    }
+    assert_eq!(server.metrics().oversize_frames(), 1);
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test trickles a frame one byte at a time, each byte well within the read
+// timeout, and checks that the frame as a whole is still held to the deadline
+
+#[test]
+fn test_read_timeout_per_frame() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .idle_timeout(Some(std::time::Duration::from_millis(300)))
+                .read_timeout(Some(std::time::Duration::from_millis(300)))
+                .send_timeout_error(true)
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    });
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 2000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    let payload = encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "slowly".to_string(),
+        })),
+        request_id: 0,
+    });
+    let started = std::time::Instant::now();
+    for byte in &payload[..payload.len() - 1] {
+        if client.send_raw(std::slice::from_ref(byte)).is_err() {
+            break; // the server may already have closed the connection
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    expect_error(client.receive(), ErrorCode::TimedOut);
+    assert!(client.receive().is_err(), "Slow client should be disconnected");
+    assert!(
+        started.elapsed() < std::time::Duration::from_millis(payload.len() as u64 * 100),
+        "The deadline should cover the whole frame, not each read"
+    );
+
+    // A silent client is told why it is disconnected
+    let mut client = client::Client::new("localhost", port.into(), 2000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    expect_error(client.receive(), ErrorCode::TimedOut);
+    assert!(client.receive().is_err(), "Idle connection should have been closed");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+fn create_limited_server(
+    runtime: &Runtime,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    policy: ConnectionLimitPolicy,
+) -> Arc<Server> {
+    runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .max_connections(max_connections)
+                .max_connections_per_ip(max_connections_per_ip)
+                .connection_limit_policy(policy)
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    })
+}
+
+fn wait_for_active_connections(server: &Server, expected: usize) {
+    let started = std::time::Instant::now();
+    while server.active_connections() != expected {
+        assert!(
+            started.elapsed() < std::time::Duration::from_secs(2),
+            "Expected {} active connections, found {}",
+            expected,
+            server.active_connections()
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+
+// this test fills the server and checks that with the reject policy the next
+// client is told so, and is served again once a connection closes
+
+#[test]
+fn test_connection_limit_reject() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_limited_server(&runtime, Some(1), None, ConnectionLimitPolicy::Reject);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut first = client::Client::new("localhost", port.into(), 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(first.echo("first").unwrap(), "first");
+    assert_eq!(server.active_connections(), 1);
+
+    let mut second = client::Client::new("localhost", port.into(), 1000);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+    expect_error(second.receive(), ErrorCode::TooManyConnections);
+    assert!(second.receive().is_err(), "Rejected client should be disconnected");
+    assert_eq!(server.metrics().refused_connections(), 1);
+
+    assert!(first.disconnect().is_ok(), "Failed to disconnect from the server");
+    wait_for_active_connections(&server, 0);
+
+    assert!(second.connect().is_ok(), "Failed to reconnect to the server");
+    assert_eq!(second.echo("second").unwrap(), "second");
+    assert_eq!(server.active_connections(), 1);
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test checks that with the default queue policy a client over the limit
+// waits in the backlog and is served once the first client disconnects
+
+#[test]
+fn test_connection_limit_queue() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_limited_server(&runtime, Some(1), None, ConnectionLimitPolicy::Queue);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut first = client::Client::new("localhost", port.into(), 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(first.echo("first").unwrap(), "first");
+
+    // The queued client connects, but its request is only read once it is accepted
+    let mut second = client::Client::new("localhost", port.into(), 2000);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "second".to_string(),
+    });
+    assert!(second.send(message).is_ok(), "Failed to send message");
+
+    let started = std::time::Instant::now();
+    let leaving = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        assert!(first.disconnect().is_ok(), "Failed to disconnect from the server");
+    });
+    match second.receive().expect("Queued client was not served").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "second"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    assert!(
+        started.elapsed() >= std::time::Duration::from_millis(250),
+        "The queued client should wait for the first one to leave"
+    );
+    leaving.join().unwrap();
+    assert_eq!(server.active_connections(), 1);
+    assert_eq!(server.metrics().refused_connections(), 0);
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+// this test checks that with the drop policy a client over the limit is closed without an answer
+
+#[test]
+fn test_connection_limit_drop() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_limited_server(&runtime, Some(1), None, ConnectionLimitPolicy::Drop);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut first = client::Client::new("localhost", port.into(), 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(first.echo("first").unwrap(), "first");
+
+    let mut second = client::Client::new("localhost", port.into(), 1000);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+    assert!(second.receive().is_err(), "Dropped client should be disconnected");
+    assert_eq!(server.metrics().refused_connections(), 1);
+    assert_eq!(first.echo("still served").unwrap(), "still served");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test checks the per-address limit, which applies even while the server has room
+
+#[test]
+fn test_connection_limit_per_ip() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_limited_server(&runtime, None, Some(2), ConnectionLimitPolicy::Queue);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut clients: Vec<_> = (0..2)
+        .map(|_| client::Client::new("localhost", port.into(), 1000))
+        .collect();
+    for client in clients.iter_mut() {
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        assert_eq!(client.echo("admitted").unwrap(), "admitted");
+    }
+
+    let mut third = client::Client::new("localhost", port.into(), 1000);
+    assert!(third.connect().is_ok(), "Failed to connect to the server");
+    expect_error(third.receive(), ErrorCode::TooManyConnections);
+    assert_eq!(server.active_connections(), 2);
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+fn create_rate_limited_server(runtime: &Runtime, rate_limit: RateLimitConfig) -> Arc<Server> {
+    runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .rate_limit(rate_limit)
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    })
+}
+
+fn expect_rate_limited(result: Result<impl std::fmt::Debug, ClientError>) {
+    match result {
+        Err(ClientError::Server { code, .. }) => assert_eq!(code, ErrorCode::RateLimited),
+        other => panic!("Expected RATE_LIMITED, but received {:?}", other),
+    }
+}
+
+
+// this test gives AddRequest a per-connection budget of two: the third add is
+// refused while echoes, which have no limit, still go through
+
+#[test]
+fn test_rate_limit_per_connection() {
+    let runtime = Runtime::new().unwrap();
+
+    // A slow refill, so no token comes back while the test runs
+    let slow = RateLimit { rate: 0.01, burst: 2 };
+    let mut rate_limit = RateLimitConfig::default();
+    rate_limit.messages.insert(
+        MessageKind::AddRequest,
+        MessageRateLimit { per_connection: Some(slow), per_ip: None },
+    );
+    let server = create_rate_limited_server(&runtime, rate_limit);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(client.add(1, 2).unwrap().result, 3);
+    assert_eq!(client.add(3, 4).unwrap().result, 7);
+    expect_rate_limited(client.add(5, 6));
+    assert_eq!(client.echo("still allowed").unwrap(), "still allowed");
+    assert_eq!(server.metrics().rate_limited_requests(), 1);
+
+    // Another connection has a budget of its own
+    let mut other = client::Client::new("localhost", port.into(), 1000);
+    assert!(other.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(other.add(5, 6).unwrap().result, 11);
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test shares a per-IP budget of three requests between two connections
+// from the same address
+
+#[test]
+fn test_rate_limit_per_ip() {
+    let runtime = Runtime::new().unwrap();
+
+    let rate_limit = RateLimitConfig {
+        per_ip: Some(RateLimit { rate: 0.01, burst: 3 }),
+        ..Default::default()
+    };
+    let server = create_rate_limited_server(&runtime, rate_limit);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut first = client::Client::new("localhost", port.into(), 1000);
+    let mut second = client::Client::new("localhost", port.into(), 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+
+    assert_eq!(first.echo("one").unwrap(), "one");
+    assert_eq!(second.echo("two").unwrap(), "two");
+    assert_eq!(first.add(1, 2).unwrap().result, 3);
+    expect_rate_limited(second.echo("three"));
+    expect_rate_limited(first.echo("four"));
+
+    // Being limited does not close the connection
+    assert!(first.is_connected());
+    assert_eq!(server.metrics().rate_limited_requests(), 2);
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test checks that a request refused by its address's budget does not
+// use up the connection's budget as well
+
+#[test]
+fn test_rate_limit_refusal_keeps_connection_budget() {
+    let runtime = Runtime::new().unwrap();
+
+    let mut rate_limit = RateLimitConfig {
+        per_connection: Some(RateLimit { rate: 0.01, burst: 3 }),
+        ..Default::default()
+    };
+    rate_limit.messages.insert(
+        MessageKind::AddRequest,
+        MessageRateLimit { per_connection: None, per_ip: Some(RateLimit { rate: 0.01, burst: 1 }) },
+    );
+    let server = create_rate_limited_server(&runtime, rate_limit);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(client.add(1, 2).unwrap().result, 3);
+    expect_rate_limited(client.add(1, 2));
+    expect_rate_limited(client.add(1, 2));
+
+    // Only the first add took from the connection's three tokens
+    assert_eq!(client.echo("two left").unwrap(), "two left");
+    assert_eq!(client.echo("one left").unwrap(), "one left");
+    expect_rate_limited(client.echo("none left"));
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+fn http_get(addr: std::net::SocketAddr, path: &str) -> String {
+    use std::io::Read;
+    let mut stream = std::net::TcpStream::connect(addr).expect("Failed to connect to the metrics listener");
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("Failed to read the metrics response");
+    response
+}
+
+
+// this test scrapes the Prometheus endpoint after some traffic and checks the
+// connection, request, decode failure, byte and latency metrics
+
+#[test]
+fn test_metrics_endpoint() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .metrics_addr("127.0.0.1:0")
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    });
+    let port = server.local_addr().unwrap().port();
+    let metrics_addr = server.metrics_addr().expect("Metrics listener not bound");
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(client.echo("measured").unwrap(), "measured");
+    assert_eq!(client.add(2, 3).unwrap().result, 5);
+    assert_eq!(client.add(4, 5).unwrap().result, 9);
+    assert!(client.send_raw(&[2, 0x0a, 0x05]).is_ok(), "Failed to send frame");
+    expect_error(client.receive(), ErrorCode::DecodeError);
+
+    let response = http_get(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    for line in [
+        "server_connections_accepted_total 1",
+        "server_connections_active 1",
+        "server_decode_failures_total 1",
+        "server_requests_total{type=\"echo_message\"} 1",
+        "server_requests_total{type=\"add_request\"} 2",
+        "server_handler_duration_seconds_count{type=\"add_request\"} 2",
+        "server_handler_duration_seconds_bucket{type=\"echo_message\",le=\"+Inf\"} 1",
+    ] {
+        assert!(response.lines().any(|l| l == line), "Missing {:?} in:\n{}", line, response);
+    }
+    assert!(server.metrics().bytes_received() > 0);
+    assert!(server.metrics().bytes_sent() > 0);
+
+    assert!(http_get(metrics_addr, "/other").starts_with("HTTP/1.1 404"));
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test lists the live connections with their counters, then forcibly
+// disconnects one of them
+
+#[test]
+fn test_connection_registry() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_server(&runtime);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut first = client::Client::new("localhost", port.into(), 1000);
+    let mut second = client::Client::new("localhost", port.into(), 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(first.echo("one").unwrap(), "one");
+    assert_eq!(first.add(1, 2).unwrap().result, 3);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(second.echo("two").unwrap(), "two");
+
+    let connections = server.connections();
+    assert_eq!(connections.len(), 2);
+    let (busy, quiet) = (&connections[0], &connections[1]);
+    assert!(busy.id < quiet.id, "Connections should be listed oldest first");
+    assert_eq!(busy.peer_addr, first.get_ref().unwrap().local_addr().unwrap());
+    assert_eq!(busy.requests, 2);
+    assert_eq!(quiet.requests, 1);
+    assert!(busy.bytes_received > quiet.bytes_received);
+    assert!(busy.bytes_sent > 0);
+    assert!(busy.last_activity >= busy.connected_at);
+
+    assert!(server.disconnect(busy.id), "Failed to disconnect {}", busy.id);
+    wait_for_active_connections(&server, 1);
+    assert!(first.echo("gone").is_err(), "A disconnected client should not be answered");
+    assert_eq!(server.connections().iter().map(|c| c.id).collect::<Vec<_>>(), [quiet.id]);
+    assert!(!server.disconnect(busy.id), "The connection is already closed");
+
+    assert_eq!(second.echo("still here").unwrap(), "still here");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+/// Pushes large messages to a client that reads nothing until one does not fit in its queue
+fn push_until_full(server: &Server, id: u64) {
+    let big = ServerMessage {
+        message: Some(server_message::Message::EchoMessage(EchoMessage { content: "x".repeat(64 * 1024) })),
+        ..Default::default()
+    };
+    // Well beyond what the socket buffers hold
+    for _ in 0..1000 {
+        if !server.send_to(id, big.clone()) {
+            return;
+        }
+    }
+    panic!("The push queue never filled up");
+}
+
+fn create_push_server(runtime: &Runtime, policy: SlowConsumerPolicy) -> Arc<Server> {
+    runtime.block_on(async {
+        Arc::new(
+            Server::builder()
+                .bind("localhost:0")
+                .push_queue_size(1)
+                .slow_consumer_policy(policy)
+                .build()
+                .await
+                .expect("Failed to start server"),
+        )
+    })
+}
+
+
+// this test pushes to a client that stops reading: under the drop policy the
+// extra messages are dropped and the connection stays open
+
+#[test]
+fn test_slow_consumer_drop() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_push_server(&runtime, SlowConsumerPolicy::Drop);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    wait_for_active_connections(&server, 1);
+    let id = server.connections()[0].id;
+
+    push_until_full(&server, id);
+    assert!(server.metrics().dropped_pushes() >= 1);
+    assert_eq!(server.connections().len(), 1, "The slow consumer should stay connected");
+
+    // The first frame the client reads is one of the pushes
+    let push = client.receive().expect("Failed to receive pushed message");
+    assert!(push.push);
+
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
+
+
+// this test pushes to a client that stops reading: under the disconnect policy
+// the connection is closed once its queue is full
+
+#[test]
+fn test_slow_consumer_disconnect() {
+    let runtime = Runtime::new().unwrap();
+
+    let server = create_push_server(&runtime, SlowConsumerPolicy::Disconnect);
+    let port = server.local_addr().unwrap().port();
+    let handle = setup_server_thread(server.clone(), &runtime);
+
+    let mut client = client::Client::new("localhost", port.into(), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    wait_for_active_connections(&server, 1);
+    let id = server.connections()[0].id;
+
+    push_until_full(&server, id);
+    wait_for_active_connections(&server, 0);
+    assert!(!server.send_to(id, ServerMessage::default()), "The connection should be gone");
+
+    server.stop();
+    runtime.block_on(async {
+        handle.await.unwrap();
+    });
+}
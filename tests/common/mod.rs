@@ -0,0 +1,14 @@
+use embedded_recruitment_task::server::Server;
+use std::sync::Arc;
+
+// Runs the server on the current tokio runtime until it is stopped
+pub fn start_server(server: Server) -> (Arc<Server>, tokio::task::JoinHandle<()>) {
+    let server = Arc::new(server);
+    let handle = tokio::spawn({
+        let server = server.clone();
+        async move {
+            server.run().await.expect("Server encountered an error");
+        }
+    });
+    (server, handle)
+}
@@ -0,0 +1,166 @@
+use embedded_recruitment_task::{
+    config::{LogFormat, RateLimit, ServerConfig, DEFAULT_MAX_FRAME_SIZE, DEFAULT_TLS_HANDSHAKE_TIMEOUT},
+    handler::{MessageKind, OverflowPolicy},
+};
+use log::LevelFilter;
+use std::time::Duration;
+
+
+// this test loads a TOML file that sets only some keys and checks that the
+// rest keep their defaults
+
+#[test]
+fn test_config_from_toml() {
+    let config = ServerConfig::from_toml_str(
+        r#"
+        bind_addr = "0.0.0.0:9000"
+        max_connections = 64
+        idle_timeout_ms = 30000
+        overflow_policy = "saturate"
+        log_level = "debug"
+        log_format = "json"
+        auth_token_file = "/etc/server/tokens.toml"
+        max_retained_topics = 500
+
+        [listener]
+        backlog = 16
+
+        [rate_limit]
+        per_ip = { rate = 100.0, burst = 200 }
+
+        [rate_limit.messages.add_request]
+        per_connection = { rate = 0.5, burst = 1 }
+
+        [ip_filter]
+        allow = ["10.20.0.0/16", "192.0.2.7"]
+
+        [acl]
+        messages.add_request = ["admin-*"]
+
+        [tls]
+        cert_path = "/etc/server/cert.pem"
+        key_path = "/etc/server/key.pem"
+        "#,
+    )
+    .expect("Failed to parse configuration");
+
+    assert_eq!(config.bind_addr, "0.0.0.0:9000");
+    assert_eq!(config.max_connections, Some(64));
+    assert_eq!(config.idle_timeout, Some(Duration::from_secs(30)));
+    assert_eq!(config.read_timeout, None);
+    assert_eq!(config.overflow_policy, OverflowPolicy::Saturate);
+    assert_eq!(config.log_level, LevelFilter::Debug);
+    assert_eq!(config.log_format, LogFormat::Json);
+    assert_eq!(config.listener.backlog, 16);
+    assert!(config.listener.nodelay, "nodelay should keep its default");
+    assert_eq!(config.max_frame_size, DEFAULT_MAX_FRAME_SIZE);
+    assert_eq!(config.rate_limit.per_connection, None);
+    assert_eq!(config.rate_limit.per_ip, Some(RateLimit { rate: 100.0, burst: 200 }));
+    let add_limits = &config.rate_limit.messages[&MessageKind::AddRequest];
+    assert_eq!(add_limits.per_connection, Some(RateLimit { rate: 0.5, burst: 1 }));
+    assert_eq!(add_limits.per_ip, None);
+    assert_eq!(config.acl.messages[&MessageKind::AddRequest], vec!["admin-*".to_string()]);
+    assert!(config.acl.allows(MessageKind::AddRequest, Some("admin-1")));
+    assert!(!config.acl.allows(MessageKind::AddRequest, None));
+    assert!(config.acl.allows(MessageKind::EchoMessage, None), "Kinds without a rule should be allowed");
+    assert_eq!(config.ip_filter.allow.len(), 2);
+    assert!(config.ip_filter.deny.is_empty());
+    let tls = config.tls.expect("TLS should be enabled");
+    assert_eq!(tls.cert_path, std::path::Path::new("/etc/server/cert.pem"));
+    assert_eq!(tls.handshake_timeout, DEFAULT_TLS_HANDSHAKE_TIMEOUT);
+    assert_eq!(config.auth_token_file.as_deref(), Some(std::path::Path::new("/etc/server/tokens.toml")));
+    assert_eq!(config.max_retained_topics, 500);
+
+    // Typos are reported instead of being silently ignored
+    let error = ServerConfig::from_toml_str("max_frame_sise = 10").unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("max_frame_sise"), "Unexpected error: {}", error);
+
+    let file = std::env::temp_dir().join(format!("server-config-{}.toml", std::process::id()));
+    std::fs::write(&file, "max_frame_size = 128").unwrap();
+    let config = ServerConfig::from_toml_file(&file).expect("Failed to read configuration file");
+    std::fs::remove_file(&file).unwrap();
+    assert_eq!(config.max_frame_size, 128);
+}
+
+
+// this test overrides settings through SERVER_* environment variables
+
+#[test]
+fn test_config_from_env() {
+    std::env::set_var("SERVER_MAX_FRAME_SIZE", "8192");
+    std::env::set_var("SERVER_WRITE_TIMEOUT_MS", "250");
+    std::env::set_var("SERVER_MAX_CONNECTIONS", "none");
+    std::env::set_var("SERVER_OVERFLOW_POLICY", "Widen");
+    std::env::set_var("SERVER_RATE_LIMIT_PER_CONNECTION", "2.5");
+    std::env::set_var("SERVER_IP_DENY", "10.20.5.0/24, 10.20.6.0/24");
+
+    let mut config = ServerConfig::from_toml_str("max_connections = 10").unwrap();
+    config.apply_env().expect("Failed to apply environment");
+
+    assert_eq!(config.max_frame_size, 8192);
+    assert_eq!(config.write_timeout, Some(Duration::from_millis(250)));
+    assert_eq!(config.max_connections, None);
+    assert_eq!(config.overflow_policy, OverflowPolicy::Widen);
+    assert_eq!(config.rate_limit.per_connection, Some(RateLimit { rate: 2.5, burst: 3 }));
+    assert_eq!(config.ip_filter.deny.len(), 2);
+
+    std::env::set_var("SERVER_TCP_NODELAY", "maybe");
+    let error = ServerConfig::from_env().unwrap_err();
+    assert!(error.to_string().contains("SERVER_TCP_NODELAY"), "Unexpected error: {}", error);
+
+    for name in [
+        "SERVER_MAX_FRAME_SIZE",
+        "SERVER_WRITE_TIMEOUT_MS",
+        "SERVER_MAX_CONNECTIONS",
+        "SERVER_OVERFLOW_POLICY",
+        "SERVER_RATE_LIMIT_PER_CONNECTION",
+        "SERVER_IP_DENY",
+        "SERVER_TCP_NODELAY",
+    ] {
+        std::env::remove_var(name);
+    }
+}
+
+
+// this test rejects rate limits that would refuse every request or never refill
+
+#[test]
+fn test_config_invalid_rate_limits() {
+    for limit in [
+        "{ rate = 0.0, burst = 10 }",
+        "{ rate = -1.0, burst = 10 }",
+        "{ rate = nan, burst = 10 }",
+        "{ rate = inf, burst = 10 }",
+        "{ rate = 5.0, burst = 0 }",
+    ] {
+        let text = format!("[rate_limit]\nper_connection = {}", limit);
+        let error = ServerConfig::from_toml_str(&text).expect_err(&format!("{} should be rejected", limit));
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    let text = "[rate_limit.messages.add_request]\nper_ip = { rate = 1.0, burst = 0 }";
+    assert!(ServerConfig::from_toml_str(text).is_err(), "Per-message limits should be checked too");
+
+    assert!("5:0".parse::<RateLimit>().is_err());
+    assert!("0".parse::<RateLimit>().is_err());
+    assert_eq!("0.5".parse::<RateLimit>(), Ok(RateLimit { rate: 0.5, burst: 1 }));
+}
+
+
+// this test rejects connection limits of 0, which would stop the server accepting anyone
+
+#[test]
+fn test_config_zero_connection_limits() {
+    for text in ["max_connections = 0", "max_connections_per_ip = 0"] {
+        let error = ServerConfig::from_toml_str(text).expect_err(&format!("{} should be rejected", text));
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    let config = ServerConfig {
+        max_connections: Some(0),
+        ..ServerConfig::default()
+    };
+    assert!(config.validate().is_err());
+    assert!(ServerConfig::from_toml_str("max_connections = 1").is_ok());
+}
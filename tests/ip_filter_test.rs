@@ -0,0 +1,101 @@
+use embedded_recruitment_task::{
+    client::Client,
+    config::{IpFilterConfig, IpNet},
+    server::Server,
+};
+use std::{net::IpAddr, time::Duration};
+
+mod common;
+
+use common::start_server;
+
+fn net(s: &str) -> IpNet {
+    s.parse().unwrap_or_else(|e| panic!("Failed to parse {}: {}", s, e))
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+
+// this test parses CIDR blocks and checks which addresses they contain
+
+#[test]
+fn test_cidr_matching() {
+    let subnet = net("10.20.0.0/16");
+    assert!(subnet.contains(ip("10.20.0.1")));
+    assert!(subnet.contains(ip("10.20.255.255")));
+    assert!(!subnet.contains(ip("10.21.0.1")));
+    assert!(!subnet.contains(ip("2001:db8::1")));
+    // IPv4 clients of a dual-stack listener arrive as IPv4-mapped IPv6 addresses
+    assert!(subnet.contains(ip("::ffff:10.20.3.4")));
+
+    assert!(net("0.0.0.0/0").contains(ip("192.0.2.1")));
+    assert!(net("2001:db8::/32").contains(ip("2001:db8:1::7")));
+    assert!(!net("2001:db8::/32").contains(ip("2001:db9::1")));
+
+    // A bare address is a block of one
+    let single = net("192.0.2.7");
+    assert_eq!(single.prefix_len(), 32);
+    assert!(single.contains(ip("192.0.2.7")));
+    assert!(!single.contains(ip("192.0.2.8")));
+    assert_eq!(net("::1").prefix_len(), 128);
+
+    // Host bits in the block's address are ignored
+    assert!(net("10.20.1.1/16").contains(ip("10.20.9.9")));
+    assert_eq!(net("10.20.0.0/16").to_string(), "10.20.0.0/16");
+
+    for invalid in ["10.20.0.0/33", "::/129", "10.20.0/16", "10.20.0.0/", "subnet"] {
+        assert!(invalid.parse::<IpNet>().is_err(), "{} should not parse", invalid);
+    }
+
+    let filter = IpFilterConfig {
+        allow: vec![net("10.20.0.0/16")],
+        deny: vec![net("10.20.5.0/24")],
+    };
+    assert!(filter.allows(ip("10.20.4.1")));
+    assert!(!filter.allows(ip("10.20.5.1")), "A denied block wins over an allowed one");
+    assert!(!filter.allows(ip("192.0.2.1")), "Addresses outside the allow list are refused");
+    assert!(IpFilterConfig::default().allows(ip("192.0.2.1")));
+}
+
+
+// this test refuses clients from a denied block at accept time, and reloads
+// the lists while the server runs
+
+#[tokio::test]
+async fn test_ip_filter_reload() {
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .ip_filter(IpFilterConfig {
+            allow: Vec::new(),
+            deny: vec![net("127.0.0.0/8")],
+        })
+        .build()
+        .await
+        .expect("Failed to start server");
+    let (server, handle) = start_server(server);
+    let addr = server.local_addr().unwrap();
+
+    let result = Client::connect_with_timeout(addr, Duration::from_secs(2)).await;
+    assert!(result.is_err(), "A denied client should be closed before the handshake");
+    assert_eq!(server.metrics().filtered_connections(), 1);
+    assert_eq!(server.metrics().accepted_connections(), 0);
+
+    server.set_ip_filter(IpFilterConfig::default());
+    let client = Client::connect(addr).await.expect("Failed to connect once the filter is lifted");
+    assert_eq!(client.echo("let in").await.unwrap(), "let in");
+
+    // A new allow list only applies to new connections
+    server.set_ip_filter(IpFilterConfig {
+        allow: vec![net("10.20.0.0/16")],
+        deny: Vec::new(),
+    });
+    assert_eq!(server.ip_filter().allow, vec![net("10.20.0.0/16")]);
+    assert!(Client::connect_with_timeout(addr, Duration::from_secs(2)).await.is_err());
+    assert_eq!(server.metrics().filtered_connections(), 2);
+    assert_eq!(client.echo("still in").await.unwrap(), "still in");
+
+    server.stop();
+    handle.await.unwrap();
+}
@@ -0,0 +1,45 @@
+use embedded_recruitment_task::{
+    message::ErrorCode,
+    pubsub::{topic_matches, validate_filter, validate_topic},
+};
+
+
+// this test checks the single and multi-level wildcards against topics of
+// different depths
+
+#[test]
+fn test_topic_matches() {
+    assert!(topic_matches("devices/42/temperature", "devices/42/temperature"));
+    assert!(!topic_matches("devices/42/temperature", "devices/42"));
+    assert!(!topic_matches("devices/42", "devices/42/temperature"));
+
+    assert!(topic_matches("devices/+/temperature", "devices/42/temperature"));
+    assert!(!topic_matches("devices/+/temperature", "devices/42/humidity"));
+    assert!(!topic_matches("devices/+", "devices/42/temperature"));
+    assert!(topic_matches("+/+", "devices/42"));
+
+    assert!(topic_matches("devices/#", "devices/42/temperature"));
+    assert!(topic_matches("devices/#", "devices"));
+    assert!(!topic_matches("devices/#", "rooms/kitchen"));
+    assert!(topic_matches("#", "anything/at/all"));
+}
+
+
+// this test rejects malformed filters and topics with INVALID_TOPIC
+
+#[test]
+fn test_topic_validation() {
+    for filter in ["devices/+/temperature", "devices/#", "#", "+"] {
+        assert!(validate_filter(filter).is_ok(), "{} should be a valid filter", filter);
+    }
+    for filter in ["", "devices/#/temperature", "devices/4+", "devices#"] {
+        let error = validate_filter(filter).expect_err(filter);
+        assert_eq!(error.code, ErrorCode::InvalidTopic);
+    }
+
+    assert!(validate_topic("devices/42/temperature").is_ok());
+    for topic in ["", "devices/+", "devices/#"] {
+        let error = validate_topic(topic).expect_err(topic);
+        assert_eq!(error.code, ErrorCode::InvalidTopic);
+    }
+}
@@ -0,0 +1,278 @@
+use async_trait::async_trait;
+use embedded_recruitment_task::{
+    client::{Client, ClientError},
+    config::{ConnectionLimitPolicy, TlsConfig},
+    handler::{ConnectionContext, Handler, HandlerError, MessageKind},
+    message::{client_message, server_message, EchoMessage, ServerMessage},
+    server::Server,
+    tls::ClientTls,
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+mod common;
+
+use common::start_server;
+
+/// PEM files of a throwaway CA and of a `localhost` certificate it issued, in a directory of their own
+struct Pki {
+    dir: PathBuf,
+    ca: Certificate,
+    ca_key: KeyPair,
+}
+
+impl Pki {
+    fn generate(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("tls-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        let server_cert = server_params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(dir.join("server.pem"), server_cert.pem()).unwrap();
+        std::fs::write(dir.join("server.key"), server_key.serialize_pem()).unwrap();
+        Pki { dir, ca, ca_key }
+    }
+
+    /// Issues a client certificate with Common Name `common_name` and the DNS SANs in `sans`,
+    /// written to `<name>.pem` and `<name>.key`
+    fn issue_client(&self, name: &str, common_name: &str, sans: &[&str]) {
+        let key = KeyPair::generate().unwrap();
+        let sans: Vec<String> = sans.iter().map(|san| san.to_string()).collect();
+        let mut params = CertificateParams::new(sans).unwrap();
+        params.distinguished_name.push(DnType::CommonName, common_name);
+        let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+        std::fs::write(self.path(&format!("{}.pem", name)), cert.pem()).unwrap();
+        std::fs::write(self.path(&format!("{}.key", name)), key.serialize_pem()).unwrap();
+    }
+
+    fn client_tls(&self, name: &str) -> ClientTls {
+        ClientTls::new(self.path("ca.pem"), "localhost")
+            .unwrap()
+            .with_client_cert(self.path(&format!("{}.pem", name)), self.path(&format!("{}.key", name)))
+            .expect("Failed to load the client certificate")
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    fn server_tls(&self) -> TlsConfig {
+        TlsConfig::new(self.path("server.pem"), self.path("server.key"))
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn create_tls_server(pki: &Pki) -> (Arc<Server>, tokio::task::JoinHandle<()>) {
+    let server = Server::builder()
+        .bind("localhost:0")
+        .tls(pki.server_tls())
+        .build()
+        .await
+        .expect("Failed to start server");
+    start_server(server)
+}
+
+async fn wait_for_handshake_failures(server: &Server, expected: u64) {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while server.metrics().tls_handshake_failures() < expected {
+        assert!(Instant::now() < deadline, "Expected {} failed TLS handshakes", expected);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+
+// this test serves requests over TLS to a client that trusts the server's CA
+
+#[tokio::test]
+async fn test_tls_echo_and_add() {
+    let pki = Pki::generate("echo");
+    let (server, handle) = create_tls_server(&pki).await;
+    let addr = server.local_addr().unwrap();
+
+    let tls = ClientTls::new(pki.path("ca.pem"), "localhost").expect("Failed to load the CA");
+    let client = Client::connect_tls(addr, &tls).await.expect("Failed to connect over TLS");
+
+    assert_eq!(client.echo("over TLS").await.unwrap(), "over TLS");
+    assert_eq!(client.echo(&"z".repeat(3000)).await.unwrap().len(), 3000);
+    assert_eq!(client.add(2, 3).await.unwrap().result, 5);
+    assert_eq!(server.connections().len(), 1);
+    client.close().await.unwrap();
+
+    assert_eq!(server.metrics().tls_handshake_failures(), 0);
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+// this test checks that the client refuses a certificate from an unknown CA or
+// issued for another name, and that a plain TCP client is not served
+
+#[tokio::test]
+async fn test_tls_verification_failures() {
+    let pki = Pki::generate("verify");
+    let other = Pki::generate("verify-other");
+    let (server, handle) = create_tls_server(&pki).await;
+    let addr = server.local_addr().unwrap();
+
+    let untrusted = ClientTls::new(other.path("ca.pem"), "localhost").unwrap();
+    match Client::connect_tls(addr, &untrusted).await {
+        Err(ClientError::Io(_)) => {}
+        other => panic!("Expected a certificate error, got {:?}", other.map(|_| ())),
+    }
+    wait_for_handshake_failures(&server, 1).await;
+
+    let wrong_name = ClientTls::new(pki.path("ca.pem"), "example.com").unwrap();
+    match Client::connect_tls(addr, &wrong_name).await {
+        Err(ClientError::Io(_)) => {}
+        other => panic!("Expected a certificate error, got {:?}", other.map(|_| ())),
+    }
+    wait_for_handshake_failures(&server, 2).await;
+
+    // The Hello of a plain client is not a TLS ClientHello, so the server closes the connection
+    assert!(Client::connect_with_timeout(addr, Duration::from_secs(2)).await.is_err());
+    wait_for_handshake_failures(&server, 3).await;
+
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+// this test checks that a TLS client over the connection limit is closed at once,
+// without the server spending a handshake on it
+
+#[tokio::test]
+async fn test_tls_connection_limit_reject() {
+    let pki = Pki::generate("limit");
+    let server = Server::builder()
+        .bind("localhost:0")
+        .tls(pki.server_tls())
+        .max_connections(Some(1))
+        .connection_limit_policy(ConnectionLimitPolicy::Reject)
+        .build()
+        .await
+        .expect("Failed to start server");
+    let (server, handle) = start_server(server);
+    let addr = server.local_addr().unwrap();
+
+    let tls = ClientTls::new(pki.path("ca.pem"), "localhost").expect("Failed to load the CA");
+    let client = Client::connect_tls(addr, &tls).await.expect("Failed to connect over TLS");
+
+    let started = Instant::now();
+    assert!(Client::connect_tls_with_timeout(addr, &tls, Duration::from_secs(5)).await.is_err());
+    assert!(started.elapsed() < Duration::from_secs(2), "The refused client should be closed at once");
+    assert_eq!(server.metrics().refused_connections(), 1);
+    assert_eq!(server.metrics().tls_handshake_failures(), 0);
+
+    assert_eq!(client.echo("still served").await.unwrap(), "still served");
+    server.stop();
+    handle.await.unwrap();
+}
+
+
+// this test checks that a server whose certificate files cannot be loaded fails to build
+
+#[tokio::test]
+async fn test_tls_invalid_certificate_files() {
+    let pki = Pki::generate("invalid");
+
+    let missing = TlsConfig::new(pki.path("missing.pem"), pki.path("server.key"));
+    let result = Server::builder().bind("localhost:0").tls(missing).build().await;
+    assert!(result.is_err(), "A missing certificate should fail the build");
+
+    // A certificate is not a private key
+    let swapped = TlsConfig::new(pki.path("server.pem"), pki.path("server.pem"));
+    let result = Server::builder().bind("localhost:0").tls(swapped).build().await;
+    assert!(result.is_err(), "A file without a key should fail the build");
+
+    assert!(ClientTls::new(pki.path("server.key"), "localhost").is_err(), "A key is not a CA certificate");
+}
+
+
+// handler that answers an echo with the identity of the connection
+
+struct WhoAmIHandler;
+
+#[async_trait]
+impl Handler for WhoAmIHandler {
+    async fn handle(
+        &self,
+        _message: client_message::Message,
+        ctx: &ConnectionContext,
+    ) -> Result<ServerMessage, HandlerError> {
+        Ok(ServerMessage {
+            message: Some(server_message::Message::EchoMessage(EchoMessage {
+                content: ctx.identity.clone().unwrap_or_default(),
+            })),
+            ..Default::default()
+        })
+    }
+}
+
+
+// this test requires client certificates: handlers see the subject or SAN of
+// the certificate as the connection's identity, and clients without a
+// certificate from the configured CA are refused
+
+#[tokio::test]
+async fn test_mutual_tls_identity() {
+    let pki = Pki::generate("mtls");
+    let other = Pki::generate("mtls-other");
+    pki.issue_client("device", "device-42", &[]);
+    pki.issue_client("sensor", "ignored", &["sensor-7.devices.local"]);
+    other.issue_client("rogue", "device-42", &[]);
+    std::fs::copy(other.path("rogue.pem"), pki.path("rogue.pem")).unwrap();
+    std::fs::copy(other.path("rogue.key"), pki.path("rogue.key")).unwrap();
+
+    let server = Server::builder()
+        .bind("localhost:0")
+        .tls(pki.server_tls().require_client_cert(pki.path("ca.pem")))
+        .handler(MessageKind::EchoMessage, WhoAmIHandler)
+        .build()
+        .await
+        .expect("Failed to start server");
+    let (server, handle) = start_server(server);
+    let addr = server.local_addr().unwrap();
+
+    // Without a SAN the Common Name is the identity
+    let device = Client::connect_tls(addr, &pki.client_tls("device"))
+        .await
+        .expect("Failed to connect with a client certificate");
+    assert_eq!(device.echo("who am I").await.unwrap(), "device-42");
+    assert_eq!(server.connections()[0].identity.as_deref(), Some("device-42"));
+
+    // A SAN takes precedence over the Common Name
+    let sensor = Client::connect_tls(addr, &pki.client_tls("sensor"))
+        .await
+        .expect("Failed to connect with a client certificate");
+    assert_eq!(sensor.echo("who am I").await.unwrap(), "sensor-7.devices.local");
+
+    let anonymous = ClientTls::new(pki.path("ca.pem"), "localhost").unwrap();
+    let result = Client::connect_tls(addr, &anonymous).await;
+    assert!(result.is_err(), "A client without a certificate should be refused");
+    wait_for_handshake_failures(&server, 1).await;
+
+    let result = Client::connect_tls(addr, &pki.client_tls("rogue")).await;
+    assert!(result.is_err(), "A certificate from another CA should be refused");
+    wait_for_handshake_failures(&server, 2).await;
+
+    assert_eq!(server.connections().len(), 2);
+    server.stop();
+    handle.await.unwrap();
+}